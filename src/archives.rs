@@ -0,0 +1,182 @@
+//! Downloading the files for _Packages_: `.deb`s into a cache of _Archives_,
+//! and the files making up source packages.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+
+use crate::checksum;
+use crate::checksum::Hashes;
+use crate::fetch::Fetcher;
+use crate::parse::Package;
+use crate::release::Release;
+
+/// A file from a _Release_, and what it should contain.
+struct Wanted<'r> {
+    release: &'r Release,
+    /// The path, relative to the [crate::release::RequestedRelease::files_base].
+    name: String,
+    size: u64,
+    hashes: Hashes,
+    dest: PathBuf,
+}
+
+/// Download the files for some binary _Packages_, and the _Releases_ they came from,
+/// returning where each file was stored.
+///
+/// Files which are already present in `archives_dir`, and correct, are not downloaded again.
+pub(crate) fn download_debs(
+    fetcher: &Fetcher,
+    archives_dir: &Path,
+    packages: &[(&Release, &Package)],
+) -> Result<Vec<PathBuf>, Error> {
+    let mut wanted = Vec::with_capacity(packages.len());
+
+    for (release, package) in packages {
+        let file = package
+            .as_bin()
+            .and_then(|bin| bin.file.as_ref())
+            .ok_or_else(|| format_err!("{} {} has no file", package.name, package.version))?;
+
+        // stored under the name it has in the pool
+        wanted.push(Wanted {
+            release,
+            name: file.name.to_string(),
+            size: file.size,
+            hashes: file.hashes,
+            dest: archives_dir.join(base_name(&file.name)?),
+        });
+    }
+
+    download_verified(fetcher, archives_dir, &wanted)
+}
+
+/// Download all the files for a source _Package_, e.g. the `.dsc`, the tarballs, and their
+/// signatures, into `dest_dir`, returning where each file was stored.
+pub(crate) fn download_source(
+    fetcher: &Fetcher,
+    dest_dir: &Path,
+    release: &Release,
+    package: &Package,
+) -> Result<Vec<PathBuf>, Error> {
+    let src = package.as_src().ok_or_else(|| {
+        format_err!(
+            "{} {} isn't a source package",
+            package.name,
+            package.version
+        )
+    })?;
+
+    let wanted = src
+        .files
+        .iter()
+        .map(|file| {
+            Ok(Wanted {
+                release,
+                name: format!("{}/{}", src.directory, file.name),
+                size: file.size,
+                hashes: file.hashes,
+                dest: dest_dir.join(base_name(&file.name)?),
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    download_verified(fetcher, dest_dir, &wanted)
+}
+
+fn download_verified(
+    fetcher: &Fetcher,
+    dir: &Path,
+    wanted: &[Wanted],
+) -> Result<Vec<PathBuf>, Error> {
+    fs::create_dir_all(dir).with_context(|_| format_err!("creating directory {:?}", dir))?;
+
+    let mut downloads = Vec::with_capacity(wanted.len());
+    let mut checks = Vec::with_capacity(wanted.len());
+
+    for file in wanted {
+        // the same decision the download would make, before we trust a cached copy
+        fetcher
+            .hash_policy
+            .check(&file.hashes)
+            .with_context(|_| format_err!("checking {}", file.name))?;
+
+        let path = &file.dest;
+
+        if path.exists() {
+            if valid(path, file).is_ok() {
+                continue;
+            }
+
+            // otherwise, the transport might think it's up to date
+            fs::remove_file(path).with_context(|_| format_err!("removing invalid {:?}", path))?;
+        }
+
+        let url = file.release.req.files_base()?.join(&file.name)?;
+        let mut download = file.release.req.download(url, path)?;
+        download.hashes = Some(file.hashes);
+        downloads.push(download);
+        checks.push(file);
+    }
+
+    fetcher.fetch(&downloads)?;
+
+    // the fetcher has checked the hashes, but doesn't know the size
+    for (download, file) in downloads.iter().zip(checks) {
+        right_size(&download.to, file)
+            .with_context(|_| format_err!("validating download of {}", download.from))?;
+    }
+
+    Ok(wanted.iter().map(|file| file.dest.to_path_buf()).collect())
+}
+
+/// The last part of a path from a listing, which must be a plausible file name.
+fn base_name(name: &str) -> Result<&str, Error> {
+    let base = name
+        .rsplit('/')
+        .next()
+        .expect("rsplit always returns something");
+    ensure!(
+        !base.is_empty() && "." != base && ".." != base,
+        "invalid file name: {:?}",
+        name
+    );
+    Ok(base)
+}
+
+fn valid(path: &Path, file: &Wanted) -> Result<(), Error> {
+    right_size(path, file)?;
+    checksum::validate(fs::File::open(path)?, file.hashes)
+}
+
+fn right_size(path: &Path, file: &Wanted) -> Result<(), Error> {
+    let len = fs::metadata(path)?.len();
+    ensure!(
+        file.size == len,
+        "wrong size: expected {}, got {}",
+        file.size,
+        len
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::base_name;
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            "foo_1.0-1_amd64.deb",
+            base_name("pool/main/f/foo/foo_1.0-1_amd64.deb").unwrap()
+        );
+        assert_eq!("foo_1.0.dsc", base_name("foo_1.0.dsc").unwrap());
+        assert!(base_name("pool/main/f/foo/").is_err());
+        assert!(base_name("pool/main/f/..").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use std::ffi::OsStr;
 use std::fs;
 use std::io;
 use std::path::Path;
@@ -9,7 +10,10 @@ use failure::ensure;
 use failure::format_err;
 use failure::Error;
 use failure::ResultExt;
+use fapt::build_dep::BuildConfig;
 use fapt::commands;
+use fapt::preferences;
+use fapt::rdepends::Relation;
 use fapt::sources_list;
 use fapt::system::System;
 
@@ -65,20 +69,153 @@ fn main() -> Result<(), failure::Error> {
                 .default_value("/var/lib/dpkg")
                 .help("dpkg database location"),
         )
+        .arg(
+            Arg::with_name("preferences")
+                .long("preferences")
+                .value_name("PREFIX")
+                .help("load apt preferences, e.g. /etc/apt/preferences, and its .d"),
+        )
+        .arg(
+            Arg::with_name("target-release")
+                .long("target-release")
+                .short("t")
+                .value_name("RELEASE")
+                .help("prefer versions from this release (e.g. 'stable')"),
+        )
         .subcommand(
             SubCommand::with_name("update").help("just fetch necessary data for specified sources"),
         )
         .subcommand(
             SubCommand::with_name("source-ninja").help("dump out all source packages as ninja"),
         )
+        .subcommand(
+            SubCommand::with_name("source")
+                .help("download the files for a source package")
+                .arg(
+                    Arg::with_name("directory")
+                        .long("directory")
+                        .short("d")
+                        .value_name("DIRECTORY")
+                        .default_value(".")
+                        .help("where to store the files"),
+                )
+                .arg(
+                    Arg::with_name("package")
+                        .value_name("PACKAGE[=VERSION]")
+                        .required(true)
+                        .help("the source package, and optionally its version"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("build-dep-check")
+                .help("list source packages whose build dependencies can't be satisfied")
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .short("P")
+                        .value_name("PROFILE")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("an active build profile, e.g. 'nocheck'"),
+                )
+                .arg(
+                    Arg::with_name("arch-only")
+                        .long("arch-only")
+                        .help("ignore Build-Depends-Indep and Build-Conflicts-Indep"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rdepends")
+                .help("list packages which refer to packages, or to anything they provide")
+                .arg(
+                    Arg::with_name("sources")
+                        .long("sources")
+                        .help("include the build dependencies of source packages"),
+                )
+                .arg(
+                    Arg::with_name("type")
+                        .long("type")
+                        .value_name("RELATIONSHIP")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("only follow e.g. 'depends' or 'pre-depends' (default: all)"),
+                )
+                .arg(
+                    Arg::with_name("depth")
+                        .long("depth")
+                        .value_name("N")
+                        .default_value("1")
+                        .help("also list what refers to those, up to this many levels"),
+                )
+                .arg(
+                    Arg::with_name("package")
+                        .value_name("PACKAGE")
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("search")
+                .help("list packages whose name or description matches all the patterns")
+                .arg(
+                    Arg::with_name("names-only")
+                        .long("names-only")
+                        .help("only match against package names"),
+                )
+                .arg(
+                    Arg::with_name("pattern")
+                        .value_name("REGEX")
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("show")
+                .help("print the control paragraphs of packages")
+                .arg(
+                    Arg::with_name("package")
+                        .value_name("PACKAGE[=VERSION]")
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("policy")
+                .help("show the priorities of versions of packages, and which would be installed")
+                .arg(
+                    Arg::with_name("package")
+                        .value_name("PACKAGE")
+                        .multiple(true)
+                        .help("packages to show; if none, show the priority of each listing"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("madison")
+                .help("list every version of packages, and where each is available from")
+                .arg(
+                    Arg::with_name("package")
+                        .value_name("PACKAGE")
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("installability")
+                .help("list binary packages which can't be installed, as deb822 paragraphs"),
+        )
         .get_matches();
 
     let mut sources_entries = Vec::with_capacity(16);
     if let Some(prefix) = matches.value_of("sources-list") {
         for prefix in expand_dot_d(prefix)? {
+            let file = io::BufReader::new(fs::File::open(&prefix)?);
             sources_entries.extend(
-                sources_list::read(io::BufReader::new(fs::File::open(&prefix)?))
-                    .with_context(|_| format_err!("loading sources.list: {:?}", prefix))?,
+                if Some(OsStr::new("sources")) == prefix.extension() {
+                    sources_list::read_deb822(file)
+                } else {
+                    sources_list::read(file)
+                }
+                .with_context(|_| format_err!("loading sources.list: {:?}", prefix))?,
             );
         }
     }
@@ -126,9 +263,26 @@ fn main() -> Result<(), failure::Error> {
     }
 
     system.set_arches(&arches);
+    system.set_progress(fapt::fetch::StderrProgress);
 
     system.set_dpkg_database(matches.value_of("system-dpkg").unwrap());
 
+    if let Some(prefix) = matches.value_of("preferences") {
+        for path in expand_dot_d(prefix)? {
+            system.add_preferences(
+                preferences::read(
+                    fs::File::open(&path)
+                        .with_context(|_| format_err!("opening preferences: {:?}", path))?,
+                )
+                .with_context(|_| format_err!("loading preferences: {:?}", path))?,
+            );
+        }
+    }
+
+    if let Some(release) = matches.value_of("target-release") {
+        system.set_default_release(release);
+    }
+
     match matches.subcommand() {
         ("source-ninja", Some(_)) => {
             commands::source_ninja(&system)?;
@@ -136,6 +290,91 @@ fn main() -> Result<(), failure::Error> {
         ("update", _) => {
             system.update()?;
         }
+        ("source", Some(matches)) => {
+            let package = matches.value_of("package").unwrap();
+            let (name, version) = match package.find('=') {
+                Some(eq) => (&package[..eq], Some(&package[eq + 1..])),
+                None => (package, None),
+            };
+            for path in
+                system.download_source(name, version, matches.value_of("directory").unwrap())?
+            {
+                println!("{}", path.display());
+            }
+        }
+        ("build-dep-check", Some(matches)) => {
+            let mut config = BuildConfig::new(arches[0].parse()?);
+            if let Some(profiles) = matches.values_of("profile") {
+                config.profiles.extend(profiles.map(|p| p.to_string()));
+            }
+            config.indep = !matches.is_present("arch-only");
+
+            for (source, why) in system.build_dep_check(&config)? {
+                println!(
+                    "{} {}: {}",
+                    source.name,
+                    source.version,
+                    why.reasons.join("; ")
+                );
+            }
+        }
+        ("rdepends", Some(matches)) => {
+            let relations = match matches.values_of("type") {
+                Some(types) => types
+                    .map(|t| t.parse::<Relation>())
+                    .collect::<Result<Vec<Relation>, Error>>()?,
+                None => Relation::ALL.to_vec(),
+            };
+            let depth = matches
+                .value_of("depth")
+                .unwrap()
+                .parse::<usize>()
+                .with_context(|_| format_err!("--depth must be a number"))?;
+
+            let index = system.reverse_dependencies(matches.is_present("sources"))?;
+            for package in matches.values_of("package").unwrap() {
+                println!("{}", package);
+                for (level, reference) in index.query(package, &relations, depth) {
+                    println!(
+                        "{:indent$}{} {} {} {}",
+                        "",
+                        reference.package,
+                        reference.version,
+                        reference.relation,
+                        reference.target,
+                        indent = level * 2
+                    );
+                }
+            }
+        }
+        ("search", Some(matches)) => {
+            let patterns: Vec<&str> = matches.values_of("pattern").unwrap().collect();
+            commands::search(&system, &patterns, matches.is_present("names-only"))?;
+        }
+        ("show", Some(matches)) => {
+            let packages: Vec<&str> = matches.values_of("package").unwrap().collect();
+            commands::show(&system, &packages)?;
+        }
+        ("policy", Some(matches)) => {
+            let packages: Vec<&str> = matches.values_of("package").into_iter().flatten().collect();
+            commands::policy(&system, &packages)?;
+        }
+        ("madison", Some(matches)) => {
+            let packages: Vec<&str> = matches.values_of("package").unwrap().collect();
+            commands::madison(&system, &packages)?;
+        }
+        ("installability", Some(_)) => {
+            for (package, why) in system.installability_check()? {
+                println!("Package: {}", package.name);
+                println!("Version: {}", package.version);
+                println!("Status: broken");
+                println!("Reasons:");
+                for reason in why.reasons {
+                    println!(" {}", reason);
+                }
+                println!();
+            }
+        }
         _ => unreachable!(),
     }
 
@@ -163,12 +402,24 @@ fn expand_dot_d<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>, Error> {
     let dot_d: PathBuf = dot_d.into();
 
     if dot_d.is_dir() {
+        let mut parts = Vec::new();
         for file in fs::read_dir(dot_d)? {
             let file = file?.path();
-            if file.is_file() && file.extension() == extension {
-                ret.push(file);
+            // deb822-style `.sources` files can live alongside classic `.list` files
+            // ..and preferences.d can contain `.pref` files
+            if file.is_file()
+                && (file.extension() == extension
+                    || (Some(OsStr::new("list")) == extension
+                        && Some(OsStr::new("sources")) == file.extension())
+                    || (extension.is_none() && Some(OsStr::new("pref")) == file.extension()))
+            {
+                parts.push(file);
             }
         }
+
+        // like apt, read parts in lexical order, as the first matching preference wins
+        parts.sort();
+        ret.extend(parts);
     }
 
     if ret.is_empty() {
@@ -0,0 +1,234 @@
+//! Checking whether the build dependencies of source _Packages_ can be satisfied,
+//! like `dose-builddebcheck`.
+
+use std::collections::HashSet;
+
+use failure::format_err;
+use failure::Error;
+
+use crate::parse::Arch;
+use crate::parse::Dependency;
+use crate::parse::Package;
+use crate::parse::SingleDependency;
+use crate::parse::Source;
+use crate::resolve::Request;
+use crate::resolve::Universe;
+
+/// The environment a package is being built in. The build and host architectures are the same.
+#[derive(Clone, Debug)]
+pub struct BuildConfig {
+    pub arch: Arch,
+    /// Active build profiles, e.g. `nocheck`, `stage1`.
+    pub profiles: HashSet<String>,
+    /// Consider `Build-Depends-Indep` and `Build-Conflicts-Indep`, i.e. build the
+    /// architecture-independent packages too.
+    pub indep: bool,
+}
+
+impl BuildConfig {
+    pub fn new(arch: Arch) -> BuildConfig {
+        BuildConfig {
+            arch,
+            profiles: HashSet::new(),
+            indep: true,
+        }
+    }
+
+    /// Whether a dependency applies, according to its arch and profile restrictions,
+    /// e.g. `foo [linux-any] <!nocheck>`.
+    pub fn applies(&self, dep: &SingleDependency) -> bool {
+        let arch_ok = if dep.arch_filter.is_empty() {
+            true
+        } else if dep.arch_filter.iter().any(|(positive, _)| *positive) {
+            dep.arch_filter
+                .iter()
+                .any(|(positive, arch)| *positive && arch.matches(&self.arch))
+        } else {
+            !dep.arch_filter
+                .iter()
+                .any(|(_, arch)| arch.matches(&self.arch))
+        };
+
+        // each `<..>` is a list of terms which must all hold; any list may hold
+        let profile_ok = dep.stage_filter.is_empty()
+            || dep.stage_filter.iter().any(|list| {
+                list.split_whitespace()
+                    .all(|term| match term.strip_prefix('!') {
+                        Some(profile) => !self.profiles.contains(profile),
+                        None => self.profiles.contains(term),
+                    })
+            });
+
+        arch_ok && profile_ok
+    }
+
+    /// Whether a source package builds anything for this architecture, from its raw
+    /// `Architecture` field, e.g. `any all`. This can't use [Package::arches], as `all`
+    /// and `any` parse to the same [Arch], but `all` only builds with `indep`.
+    pub fn builds(&self, architecture: &str) -> Result<bool, Error> {
+        for arch in architecture.split_whitespace() {
+            let builds = match arch {
+                "all" => self.indep,
+                "any" => true,
+                arch => arch.parse::<Arch>()?.matches(&self.arch),
+            };
+
+            if builds {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// The requests which must be satisfiable to build the package.
+    pub fn requests(&self, source: &Source) -> Vec<Request> {
+        let mut depends = vec![&source.build_dep, &source.build_dep_arch];
+        let mut conflicts = vec![&source.build_conflict, &source.build_conflict_arch];
+        if self.indep {
+            depends.push(&source.build_dep_indep);
+            conflicts.push(&source.build_conflict_indep);
+        }
+
+        // every build environment has `build-essential`, but it's not listed
+        let mut requests = vec![Request::Install(SingleDependency {
+            package: "build-essential".to_string(),
+            ..SingleDependency::default()
+        })];
+
+        for dep in depends.into_iter().flatten() {
+            let alternate: Vec<SingleDependency> = dep
+                .alternate
+                .iter()
+                .filter(|alt| self.applies(alt))
+                .cloned()
+                .collect();
+
+            // if every alternative is restricted away, the whole dependency is dropped
+            if !alternate.is_empty() {
+                requests.push(Request::Satisfy(Dependency { alternate }));
+            }
+        }
+
+        for dep in conflicts.into_iter().flatten() {
+            for alt in dep.alternate.iter().filter(|alt| self.applies(alt)) {
+                requests.push(Request::Avoid(alt.clone()));
+            }
+        }
+
+        requests
+    }
+
+    /// Check the build dependencies of `source` can be satisfied from the `universe`,
+    /// failing with [crate::resolve::Unsatisfiable] if they can't.
+    pub fn check(&self, universe: &Universe, source: &Package) -> Result<(), Error> {
+        let src = source.as_src().ok_or_else(|| {
+            format_err!("{} {} isn't a source package", source.name, source.version)
+        })?;
+
+        universe.plan(&self.requests(src))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use failure::Error;
+
+    use super::BuildConfig;
+    use crate::parse::test_package;
+    use crate::parse::Package;
+    use crate::resolve::Universe;
+    use crate::resolve::Unsatisfiable;
+    use crate::rfc822;
+
+    fn parse(block: &str) -> Package {
+        Package::parse(&mut rfc822::fields_in_block(block).collect_to_map().unwrap()).unwrap()
+    }
+
+    fn src(build_depends: &str) -> Package {
+        parse(&format!(
+            "Package: foo\nBinary: foo\nVersion: 1\nArchitecture: any\nSection: misc\n\
+             Maintainer: Foo <foo@example.com>\nFormat: 3.0 (native)\nDirectory: pool/f/foo\n\
+             Files:\n 00000000000000000000000000000000 1 foo_1.tar.xz\n{}",
+            build_depends
+        ))
+    }
+
+    #[test]
+    fn builds() -> Result<(), Error> {
+        let mut config = BuildConfig::new("amd64".parse()?);
+        assert!(config.builds("any")?);
+        assert!(config.builds("all")?);
+        assert!(config.builds("linux-any all")?);
+        assert!(!config.builds("hurd-any")?);
+
+        config.indep = false;
+        assert!(!config.builds("all")?);
+        assert!(config.builds("any all")?);
+        assert!(config.builds("amd64")?);
+        Ok(())
+    }
+
+    #[test]
+    fn build_essential() -> Result<(), Error> {
+        let mut universe = Universe::new();
+        universe.add_available(test_package("debhelper", "1", ""))?;
+
+        let config = BuildConfig::new("amd64".parse()?);
+        let err = config
+            .check(&universe, &src("Build-Depends: debhelper\n"))
+            .unwrap_err();
+        assert_eq!(
+            vec![
+                "installing build-essential was requested",
+                "but no available package satisfies that",
+            ],
+            err.downcast::<Unsatisfiable>()?.reasons
+        );
+
+        universe.add_available(test_package("build-essential", "1", ""))?;
+        config.check(&universe, &src("Build-Depends: debhelper\n"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn restrictions() -> Result<(), Error> {
+        let mut universe = Universe::new();
+        universe.add_available(test_package("build-essential", "1", ""))?;
+        universe.add_available(test_package("debhelper", "1", ""))?;
+        universe.add_available(test_package("libc-dev", "1", ""))?;
+        universe.add_available(test_package("check", "1", ""))?;
+
+        let mut config = BuildConfig::new("amd64".parse()?);
+
+        config.check(
+            &universe,
+            &src("Build-Depends: debhelper, hurd-dev [hurd-any], check <!nocheck>\n"),
+        )?;
+
+        let conflicting = src("Build-Depends: check\nBuild-Conflicts: check [linux-any]\n");
+        assert!(config.check(&universe, &conflicting).is_err());
+        config.check(
+            &universe,
+            &src("Build-Depends: check\nBuild-Conflicts: check [!amd64]\n"),
+        )?;
+
+        let tests = src("Build-Depends: debhelper, cunit <!nocheck>\nBuild-Depends-Indep: docs\n");
+        let err = config.check(&universe, &tests).unwrap_err();
+        assert_eq!(
+            vec![
+                "installing cunit was requested",
+                "but no available package satisfies that",
+            ],
+            err.downcast::<Unsatisfiable>()?.reasons
+        );
+
+        config.profiles.insert("nocheck".to_string());
+        assert!(config.check(&universe, &tests).is_err());
+        config.indep = false;
+        config.check(&universe, &tests)?;
+
+        Ok(())
+    }
+}
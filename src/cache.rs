@@ -0,0 +1,575 @@
+//! A binary cache of every _Block_ in the _Listings_, like apt's `pkgcache.bin`, which is
+//! memory-mapped, and can be queried without parsing any deb822.
+//!
+//! The file is a header, some tables of little-endian `u32`s, then the strings they refer
+//! to. Strings are stored as an offset into the strings, and a length. The tables are:
+//!
+//!  * `listings`: the local name of each _Listing_, i.e. the hash of its content,
+//!  * `versions`: a record for each _Block_, in _Listing_ order,
+//!  * `by_name`, `by_source`: indexes into `versions`, sorted by name, and by source name,
+//!  * `provided`: the names each version provides, and which version provides it,
+//!  * `by_provides`: indexes into `provided`, sorted by name.
+
+use std::convert::TryInto;
+use std::fs;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+use std::str;
+
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+use memmap2::Mmap;
+use tempfile_fast::PersistableTempFile;
+
+use crate::parse::Package;
+use crate::rfc822;
+use crate::rfc822::RfcMapExt;
+
+const MAGIC: &[u8; 8] = b"fapt-pc1";
+
+/// A digest of the _Listings_ the cache was built from.
+pub type Key = [u8; 32];
+
+const KEY_AT: usize = MAGIC.len();
+const TABLES_AT: usize = KEY_AT + 32;
+const TABLES: usize = 7;
+const HEADER: usize = TABLES_AT + TABLES * 8;
+
+// the widths of the rows in each table, in words
+const LISTING: usize = 2;
+const VERSION: usize = 14;
+const INDEX: usize = 1;
+const PROVIDED: usize = 3;
+
+// the fields of a version
+const NAME: usize = 0;
+const VERSION_STRING: usize = 2;
+const ARCH: usize = 4;
+const SOURCE: usize = 6;
+const BLOCK: usize = 8;
+const LISTING_NO: usize = 10;
+const FLAGS: usize = 11;
+const PROVIDES_START: usize = 12;
+const PROVIDES_COUNT: usize = 13;
+
+const FLAG_SOURCE: u32 = 1;
+
+/// Collects _Blocks_, then writes a cache file.
+#[derive(Default)]
+pub struct Builder {
+    listings: Vec<u32>,
+    versions: Vec<u32>,
+    provided: Vec<u32>,
+    strings: Vec<u8>,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    fn string(&mut self, s: &str) -> Result<[u32; 2], Error> {
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(s.as_bytes());
+        ensure!(
+            self.strings.len() <= u32::MAX as usize,
+            "package cache too large"
+        );
+        Ok([offset as u32, s.len() as u32])
+    }
+
+    /// Start a new _Listing_, which the following _Blocks_ are from.
+    pub fn add_listing(&mut self, local_name: &str) -> Result<(), Error> {
+        let name = self.string(local_name)?;
+        self.listings.extend_from_slice(&name);
+        Ok(())
+    }
+
+    /// Add a _Block_ from the current _Listing_, either a binary or a source package.
+    pub fn add_block(&mut self, block: &str, is_source: bool) -> Result<(), Error> {
+        ensure!(!self.listings.is_empty(), "blocks must be in a listing");
+        let map = rfc822::fields_in_block(block).collect_to_map()?;
+
+        let name = map.get_value("Package").one_line_req()?;
+        let version = map.get_value("Version").one_line_req()?;
+        let arch = map.get_value("Architecture").one_line()?.unwrap_or("");
+
+        // e.g. `Source: foo (1.2-3)`, when the version is different
+        let source = match map.get_value("Source").one_line()? {
+            Some(source) if !is_source => source.split_whitespace().next().unwrap_or(name),
+            _ => name,
+        };
+
+        let provides = match map.get("Provides") {
+            Some(_) if !is_source => map.get_value("Provides").split_comma()?,
+            _ => Vec::new(),
+        };
+
+        let version_no = (self.versions.len() / VERSION) as u32;
+        let provides_start = (self.provided.len() / PROVIDED) as u32;
+        for provide in &provides {
+            // e.g. `foo (= 1.2)`
+            let provide = provide
+                .split(|c: char| c.is_whitespace() || '(' == c)
+                .next()
+                .unwrap_or(provide);
+            let provide = self.string(provide)?;
+            self.provided.extend_from_slice(&provide);
+            self.provided.push(version_no);
+        }
+
+        let mut record = Vec::with_capacity(VERSION);
+        for field in &[name, version, arch, source, block] {
+            record.extend_from_slice(&self.string(field)?);
+        }
+        record.push((self.listings.len() / LISTING - 1) as u32);
+        record.push(if is_source { FLAG_SOURCE } else { 0 });
+        record.push(provides_start);
+        record.push(provides.len() as u32);
+
+        self.versions.extend(record);
+        Ok(())
+    }
+
+    fn sorted_by(&self, rows: usize, width: usize, field: usize, table: &[u32]) -> Vec<u32> {
+        let string = |row: usize| {
+            let offset = table[row * width + field] as usize;
+            let len = table[row * width + field + 1] as usize;
+            &self.strings[offset..offset + len]
+        };
+
+        let mut ret: Vec<u32> = (0..rows as u32).collect();
+        ret.sort_by(|&left, &right| string(left as usize).cmp(string(right as usize)));
+        ret
+    }
+
+    /// Write the cache to `path`, replacing it atomically.
+    pub fn write<P: AsRef<Path>>(self, key: &Key, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let versions = self.versions.len() / VERSION;
+        let provided = self.provided.len() / PROVIDED;
+
+        let by_name = self.sorted_by(versions, VERSION, NAME, &self.versions);
+        let by_source = self.sorted_by(versions, VERSION, SOURCE, &self.versions);
+        let by_provides = self.sorted_by(provided, PROVIDED, 0, &self.provided);
+
+        let tables: [(&[u32], usize); TABLES - 1] = [
+            (&self.listings, self.listings.len() / LISTING),
+            (&self.versions, versions),
+            (&by_name, versions),
+            (&by_source, versions),
+            (&self.provided, provided),
+            (&by_provides, provided),
+        ];
+
+        let mut header = Vec::with_capacity(HEADER);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(key);
+
+        let mut offset = HEADER;
+        for (table, rows) in &tables {
+            header.extend_from_slice(&(offset as u32).to_le_bytes());
+            header.extend_from_slice(&(*rows as u32).to_le_bytes());
+            offset += table.len() * 4;
+        }
+        header.extend_from_slice(&(offset as u32).to_le_bytes());
+        header.extend_from_slice(&(self.strings.len() as u32).to_le_bytes());
+        ensure!(
+            offset + self.strings.len() <= u32::MAX as usize,
+            "package cache too large"
+        );
+
+        let parent = path
+            .parent()
+            .ok_or_else(|| format_err!("path must have parent"))?;
+        let mut temp = PersistableTempFile::new_in(parent)
+            .with_context(|_| format_err!("couldn't create temporary file"))?;
+
+        let mut out = std::io::BufWriter::new(&mut *temp);
+        out.write_all(&header)?;
+        for (table, _) in &tables {
+            for word in table.iter() {
+                out.write_all(&word.to_le_bytes())?;
+            }
+        }
+        out.write_all(&self.strings)?;
+        out.flush()?;
+        drop(out);
+
+        temp.persist_by_rename(path)
+            .map_err(|e| e.error)
+            .with_context(|_| format_err!("persisting {:?}", path))?;
+
+        Ok(())
+    }
+}
+
+/// Where a table is in the file.
+#[derive(Copy, Clone, Debug)]
+struct Table {
+    offset: usize,
+    rows: usize,
+    width: usize,
+}
+
+/// An open cache file.
+pub struct PackageCache {
+    map: Mmap,
+    listings: Table,
+    versions: Table,
+    by_name: Table,
+    by_source: Table,
+    provided: Table,
+    by_provides: Table,
+    strings: Range<usize>,
+}
+
+impl PackageCache {
+    /// Map a cache file, checking it's well-formed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PackageCache, Error> {
+        let path = path.as_ref();
+        let file = fs::File::open(path).with_context(|_| format_err!("opening {:?}", path))?;
+
+        // the cache is only ever replaced by renaming, never modified in place
+        let map =
+            unsafe { Mmap::map(&file) }.with_context(|_| format_err!("mapping {:?}", path))?;
+
+        Ok(PackageCache::from_map(map).with_context(|_| format_err!("reading {:?}", path))?)
+    }
+
+    fn from_map(map: Mmap) -> Result<PackageCache, Error> {
+        ensure!(
+            map.len() >= HEADER && MAGIC[..] == map[..MAGIC.len()],
+            "not a package cache, or from a different version"
+        );
+
+        let table = |no: usize, width: usize| -> Result<Table, Error> {
+            let table = Table {
+                offset: word(&map, TABLES_AT + no * 8),
+                rows: word(&map, TABLES_AT + no * 8 + 4),
+                width,
+            };
+            ensure!(
+                table.offset + table.rows * width * 4 <= map.len(),
+                "truncated"
+            );
+            Ok(table)
+        };
+
+        let strings = table(6, 0)?;
+        let strings = strings.offset..strings.offset + word(&map, TABLES_AT + 6 * 8 + 4);
+        ensure!(strings.end <= map.len(), "truncated");
+
+        let cache = PackageCache {
+            listings: table(0, LISTING)?,
+            versions: table(1, VERSION)?,
+            by_name: table(2, INDEX)?,
+            by_source: table(3, INDEX)?,
+            provided: table(4, PROVIDED)?,
+            by_provides: table(5, INDEX)?,
+            strings,
+            map,
+        };
+
+        cache.validate()?;
+        Ok(cache)
+    }
+
+    /// Check every reference is in range, so the accessors can't fail.
+    fn validate(&self) -> Result<(), Error> {
+        let string_ok = |table: Table, row: usize, field: usize| -> bool {
+            let offset = self.field(table, row, field);
+            let len = self.field(table, row, field + 1);
+            offset + len <= self.strings.len() && str::from_utf8(self.bytes(offset, len)).is_ok()
+        };
+
+        for row in 0..self.listings.rows {
+            ensure!(string_ok(self.listings, row, 0), "invalid listing {}", row);
+        }
+
+        for row in 0..self.versions.rows {
+            let valid = [NAME, VERSION_STRING, ARCH, SOURCE, BLOCK]
+                .iter()
+                .all(|&field| string_ok(self.versions, row, field))
+                && self.field(self.versions, row, LISTING_NO) < self.listings.rows
+                && self.field(self.versions, row, PROVIDES_START)
+                    + self.field(self.versions, row, PROVIDES_COUNT)
+                    <= self.provided.rows;
+            ensure!(valid, "invalid version {}", row);
+        }
+
+        for row in 0..self.provided.rows {
+            ensure!(
+                string_ok(self.provided, row, 0)
+                    && self.field(self.provided, row, 2) < self.versions.rows,
+                "invalid provides {}",
+                row
+            );
+        }
+
+        for (index, target) in &[
+            (self.by_name, self.versions.rows),
+            (self.by_source, self.versions.rows),
+            (self.by_provides, self.provided.rows),
+        ] {
+            ensure!(
+                (0..index.rows).all(|row| self.field(*index, row, 0) < *target),
+                "invalid index"
+            );
+        }
+
+        Ok(())
+    }
+
+    fn field(&self, table: Table, row: usize, field: usize) -> usize {
+        word(&self.map, table.offset + (row * table.width + field) * 4)
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> &[u8] {
+        &self.map[self.strings.start + offset..self.strings.start + offset + len]
+    }
+
+    fn string(&self, table: Table, row: usize, field: usize) -> &str {
+        let bytes = self.bytes(
+            self.field(table, row, field),
+            self.field(table, row, field + 1),
+        );
+        str::from_utf8(bytes).expect("validated on open")
+    }
+
+    /// The key the cache was built with.
+    pub fn key(&self) -> &[u8] {
+        &self.map[KEY_AT..TABLES_AT]
+    }
+
+    /// The local names of the _Listings_, in order, i.e. the hashes of their content.
+    pub fn listings(&self) -> impl Iterator<Item = &str> {
+        (0..self.listings.rows).map(move |row| self.string(self.listings, row, 0))
+    }
+
+    /// Every version, in _Listing_ order.
+    pub fn versions(&self) -> impl Iterator<Item = CachedVersion<'_>> {
+        (0..self.versions.rows).map(move |index| CachedVersion { cache: self, index })
+    }
+
+    /// The rows of an index whose target has the name `key`.
+    fn find<'s, F>(
+        &'s self,
+        index: Table,
+        key: &str,
+        name_of: F,
+    ) -> impl Iterator<Item = usize> + 's
+    where
+        F: Fn(usize) -> &'s str,
+    {
+        let key = key.as_bytes();
+        let name = |row: usize| name_of(self.field(index, row, 0)).as_bytes();
+
+        let (mut low, mut high) = (0, index.rows);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if name(mid) < key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        let end = (low..index.rows)
+            .find(|&row| name(row) != key)
+            .unwrap_or(index.rows);
+
+        (low..end).map(move |row| self.field(index, row, 0))
+    }
+
+    /// The versions of the binary and source packages with this name.
+    pub fn by_name(&self, name: &str) -> Vec<CachedVersion<'_>> {
+        self.find(self.by_name, name, |index| {
+            self.string(self.versions, index, NAME)
+        })
+        .map(|index| CachedVersion { cache: self, index })
+        .collect()
+    }
+
+    /// The versions of the source package with this name, and of the binary packages built from it.
+    pub fn by_source(&self, source: &str) -> Vec<CachedVersion<'_>> {
+        self.find(self.by_source, source, |index| {
+            self.string(self.versions, index, SOURCE)
+        })
+        .map(|index| CachedVersion { cache: self, index })
+        .collect()
+    }
+
+    /// The versions which provide this name.
+    pub fn by_provides(&self, name: &str) -> Vec<CachedVersion<'_>> {
+        self.find(self.by_provides, name, |row| {
+            self.string(self.provided, row, 0)
+        })
+        .map(|row| CachedVersion {
+            cache: self,
+            index: self.field(self.provided, row, 2),
+        })
+        .collect()
+    }
+}
+
+fn word(bytes: &[u8], at: usize) -> usize {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().expect("four bytes")) as usize
+}
+
+/// A version of a package, i.e. a _Block_, in a [PackageCache].
+#[derive(Copy, Clone)]
+pub struct CachedVersion<'c> {
+    cache: &'c PackageCache,
+    index: usize,
+}
+
+impl<'c> CachedVersion<'c> {
+    fn string(&self, field: usize) -> &'c str {
+        self.cache.string(self.cache.versions, self.index, field)
+    }
+
+    pub fn name(&self) -> &'c str {
+        self.string(NAME)
+    }
+
+    pub fn version(&self) -> &'c str {
+        self.string(VERSION_STRING)
+    }
+
+    /// The `Architecture` field, which is a list for source packages.
+    pub fn arch(&self) -> &'c str {
+        self.string(ARCH)
+    }
+
+    /// The name of the source package, which is the name, for source packages.
+    pub fn source(&self) -> &'c str {
+        self.string(SOURCE)
+    }
+
+    pub fn is_source(&self) -> bool {
+        0 != (self.cache.field(self.cache.versions, self.index, FLAGS) as u32 & FLAG_SOURCE)
+    }
+
+    /// Which _Listing_ this is from, as an index into [PackageCache::listings].
+    pub fn listing(&self) -> usize {
+        self.cache
+            .field(self.cache.versions, self.index, LISTING_NO)
+    }
+
+    /// The names this provides, without any versions.
+    pub fn provides(&self) -> impl Iterator<Item = &'c str> {
+        let cache = self.cache;
+        let start = cache.field(cache.versions, self.index, PROVIDES_START);
+        let count = cache.field(cache.versions, self.index, PROVIDES_COUNT);
+        (start..start + count).map(move |row| cache.string(cache.provided, row, 0))
+    }
+
+    /// The _Block_ exactly as it was in the _Listing_.
+    pub fn block(&self) -> &'c str {
+        self.string(BLOCK)
+    }
+
+    /// Parse the whole _Block_.
+    pub fn as_pkg(&self) -> Result<Package, Error> {
+        Package::parse(&mut rfc822::fields_in_block(self.block()).collect_to_map()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::parse::test_block;
+
+    use super::word;
+    use super::Builder;
+    use super::PackageCache;
+    use super::TABLES_AT;
+
+    #[test]
+    fn round_trip() {
+        let mut builder = Builder::new();
+        builder.add_listing("aaaa").unwrap();
+        builder
+            .add_block(
+                &test_block(
+                    "libfoo1",
+                    "1.2-3",
+                    "Source: foo (1.2-2)\nProvides: libfoo-abi-1, libfoo (= 1.2)\n",
+                ),
+                false,
+            )
+            .unwrap();
+        builder
+            .add_block(
+                "Package: foo-bin\nVersion: 1.2-3\nArchitecture: amd64\nSource: foo\n",
+                false,
+            )
+            .unwrap();
+        builder.add_listing("bbbb").unwrap();
+        builder
+            .add_block("Package: foo\nVersion: 1.2-3\nArchitecture: any\n", true)
+            .unwrap();
+        builder
+            .add_block("Package: bar\nVersion: 1\nArchitecture: all\n", true)
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pkgcache.bin");
+        builder.write(&[7; 32], &path).unwrap();
+
+        let cache = PackageCache::open(&path).unwrap();
+        assert_eq!(&[7; 32], cache.key());
+        assert_eq!(vec!["aaaa", "bbbb"], cache.listings().collect::<Vec<_>>());
+        assert_eq!(4, cache.versions().count());
+
+        let lib = cache.by_name("libfoo1");
+        assert_eq!(1, lib.len());
+        assert_eq!("1.2-3", lib[0].version());
+        assert_eq!("foo", lib[0].source());
+        assert_eq!(0, lib[0].listing());
+        assert!(!lib[0].is_source());
+        assert_eq!(
+            vec!["libfoo-abi-1", "libfoo"],
+            lib[0].provides().collect::<Vec<_>>()
+        );
+        assert_eq!("libfoo1", lib[0].as_pkg().unwrap().name);
+
+        let mut source: Vec<&str> = cache.by_source("foo").iter().map(|v| v.name()).collect();
+        source.sort();
+        assert_eq!(vec!["foo", "foo-bin", "libfoo1"], source);
+
+        let foo = cache.by_name("foo");
+        assert_eq!(1, foo.len());
+        assert!(foo[0].is_source());
+        assert_eq!(1, foo[0].listing());
+
+        assert_eq!("libfoo1", cache.by_provides("libfoo")[0].name());
+        assert!(cache.by_name("libfoo").is_empty());
+        assert!(cache.by_provides("nothing").is_empty());
+
+        // damage is an error on open, not a panic on use
+        let original = fs::read(&path).unwrap();
+
+        let mut bad_string = original.clone();
+        *bad_string.last_mut().unwrap() = 0xff;
+        fs::write(&path, &bad_string).unwrap();
+        assert!(PackageCache::open(&path).is_err());
+
+        let mut bad_index = original.clone();
+        let by_name = word(&original, TABLES_AT + 2 * 8);
+        bad_index[by_name..by_name + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &bad_index).unwrap();
+        assert!(PackageCache::open(&path).is_err());
+
+        fs::write(&path, &original).unwrap();
+        assert!(PackageCache::open(&path).is_ok());
+
+        fs::write(&path, b"fapt-pc1 but truncated").unwrap();
+        assert!(PackageCache::open(&path).is_err());
+    }
+}
@@ -1,72 +1,214 @@
+//! The hashes of files, as listed in _Release_ files, _Packages_ and _Sources_.
+
 use std::fmt;
 use std::io;
 use std::io::Read;
 
+use failure::bail;
 use failure::ensure;
 use failure::Error;
 use hex;
-use hex::FromHex;
+use md5::Md5;
+use sha1::Sha1;
 use sha2::Digest;
 use sha2::Sha256;
+use sha2::Sha512;
 
 pub type MD5 = [u8; 16];
+pub type SHA1 = [u8; 20];
 pub type SHA256 = [u8; 32];
+pub type SHA512 = [u8; 64];
 
-#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+/// All of the hashes we know for a file. Repositories rarely provide every type.
+#[derive(Copy, Clone, Default, Hash, PartialEq, Eq)]
 pub struct Hashes {
-    pub md5: MD5,
-    pub sha256: SHA256,
+    pub md5: Option<MD5>,
+    pub sha1: Option<SHA1>,
+    pub sha256: Option<SHA256>,
+    pub sha512: Option<SHA512>,
+}
+
+impl Hashes {
+    pub fn is_empty(&self) -> bool {
+        self.md5.is_none() && self.sha1.is_none() && !self.has_strong()
+    }
+
+    /// Whether any of the hashes are of a type that is still considered secure.
+    pub fn has_strong(&self) -> bool {
+        self.sha256.is_some() || self.sha512.is_some()
+    }
 }
 
 impl fmt::Debug for Hashes {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "md5:{} sha256:{}",
-            hex::encode(self.md5),
-            hex::encode(self.sha256)
-        )
+        let parts: Vec<String> = [
+            ("md5", self.md5.map(hex::encode)),
+            ("sha1", self.sha1.map(hex::encode)),
+            ("sha256", self.sha256.map(hex::encode)),
+            ("sha512", self.sha512.map(|v| hex::encode(&v[..]))),
+        ]
+        .iter()
+        .filter_map(|(name, value)| value.as_ref().map(|value| format!("{}:{}", name, value)))
+        .collect();
+
+        if parts.is_empty() {
+            write!(f, "no hashes")
+        } else {
+            write!(f, "{}", parts.join(" "))
+        }
     }
 }
 
-pub fn parse_md5(hash: &str) -> Result<MD5, Error> {
-    let mut arr = [0u8; 16];
-    let v = Vec::from_hex(hash)?;
+/// Which hashes are acceptable evidence that a file is intact.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HashPolicy {
+    /// Any hash will do, even MD5 or SHA1 alone.
+    Any,
+    /// Refuse files which only have MD5 or SHA1 hashes, i.e. require SHA256 or SHA512.
+    #[default]
+    Strong,
+}
+
+impl HashPolicy {
+    pub fn check(self, hashes: &Hashes) -> Result<(), Error> {
+        match self {
+            HashPolicy::Any => ensure!(!hashes.is_empty(), "no hashes available"),
+            HashPolicy::Strong => ensure!(
+                hashes.has_strong(),
+                "only weak hashes available, refusing: {:?}",
+                hashes
+            ),
+        }
+        Ok(())
+    }
+}
+
+fn parse_into(name: &str, hash: &str, into: &mut [u8]) -> Result<(), Error> {
+    let v = hex::decode(hash)?;
     ensure!(
-        arr.len() == v.len(),
-        "a md5 checksum isn't the right length? {}",
+        into.len() == v.len(),
+        "a {} checksum isn't the right length? {}",
+        name,
         hash
     );
-    arr.copy_from_slice(&v);
+    into.copy_from_slice(&v);
+    Ok(())
+}
 
+pub fn parse_md5(hash: &str) -> Result<MD5, Error> {
+    let mut arr = [0u8; 16];
+    parse_into("md5", hash, &mut arr)?;
+    Ok(arr)
+}
+
+pub fn parse_sha1(hash: &str) -> Result<SHA1, Error> {
+    let mut arr = [0u8; 20];
+    parse_into("sha1", hash, &mut arr)?;
     Ok(arr)
 }
 
 pub fn parse_sha256(hash: &str) -> Result<SHA256, Error> {
     let mut arr = [0u8; 32];
+    parse_into("sha256", hash, &mut arr)?;
+    Ok(arr)
+}
 
-    let v = Vec::from_hex(hash)?;
-    ensure!(
-        arr.len() == v.len(),
-        "a sha256 checksum isn't the right length? {}",
-        hash
-    );
+pub fn parse_sha512(hash: &str) -> Result<SHA512, Error> {
+    let mut arr = [0u8; 64];
+    parse_into("sha512", hash, &mut arr)?;
+    Ok(arr)
+}
 
-    arr.copy_from_slice(&v);
+/// Check the data matches every hash in `expected`, reading it only once.
+pub fn validate<R: Read>(mut file: R, expected: Hashes) -> Result<(), Error> {
+    ensure!(!expected.is_empty(), "no hashes to validate against");
 
-    Ok(arr)
+    let mut md5 = expected.md5.map(|_| Md5::default());
+    let mut sha1 = expected.sha1.map(|_| Sha1::default());
+    let mut sha256 = expected.sha256.map(|_| Sha256::default());
+    let mut sha512 = expected.sha512.map(|_| Sha512::default());
+
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref e) if io::ErrorKind::Interrupted == e.kind() => continue,
+            Err(e) => bail!(e),
+        };
+        let data = &buf[..read];
+
+        if let Some(d) = md5.as_mut() {
+            d.input(data);
+        }
+        if let Some(d) = sha1.as_mut() {
+            d.input(data);
+        }
+        if let Some(d) = sha256.as_mut() {
+            d.input(data);
+        }
+        if let Some(d) = sha512.as_mut() {
+            d.input(data);
+        }
+    }
+
+    compare("md5", expected.md5.as_ref().map(|v| &v[..]), md5)?;
+    compare("sha1", expected.sha1.as_ref().map(|v| &v[..]), sha1)?;
+    compare("sha256", expected.sha256.as_ref().map(|v| &v[..]), sha256)?;
+    compare("sha512", expected.sha512.as_ref().map(|v| &v[..]), sha512)?;
+
+    Ok(())
 }
 
-// TODO: also check the md5?
-pub fn validate<R: Read>(mut file: R, checksum: Hashes) -> Result<(), Error> {
-    let mut func = Sha256::default();
-    io::copy(&mut file, &mut func)?;
-    let result = func.result();
-    ensure!(
-        checksum.sha256 == result.as_slice(),
-        "checksum mismatch: expected: {}, actual: {}",
-        hex::encode(checksum.sha256),
-        hex::encode(result.as_slice())
-    );
+fn compare<D: Digest>(name: &str, expected: Option<&[u8]>, digest: Option<D>) -> Result<(), Error> {
+    if let (Some(expected), Some(digest)) = (expected, digest) {
+        let actual = digest.result();
+        ensure!(
+            expected == actual.as_slice(),
+            "{} mismatch: expected: {}, actual: {}",
+            name,
+            hex::encode(expected),
+            hex::encode(actual.as_slice())
+        );
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::validate;
+    use super::HashPolicy;
+    use super::Hashes;
+
+    #[test]
+    fn all_hashes() {
+        let data = &b"hello\n"[..];
+        let hashes = Hashes {
+            md5: Some(super::parse_md5("b1946ac92492d2347c6235b4d2611184").unwrap()),
+            sha1: Some(super::parse_sha1("f572d396fae9206628714fb2ce00f72e94f2258f").unwrap()),
+            sha256: Some(
+                super::parse_sha256(
+                    "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03",
+                )
+                .unwrap(),
+            ),
+            sha512: None,
+        };
+
+        validate(data, hashes).unwrap();
+
+        let mut wrong = hashes;
+        wrong.md5 = Some([0; 16]);
+        assert!(validate(data, wrong).is_err());
+
+        assert!(validate(data, Hashes::default()).is_err());
+
+        let weak = Hashes {
+            sha256: None,
+            ..hashes
+        };
+        HashPolicy::Any.check(&weak).unwrap();
+        assert!(HashPolicy::Strong.check(&weak).is_err());
+        HashPolicy::Strong.check(&hashes).unwrap();
+    }
+}
@@ -1,13 +1,24 @@
 //! Higher level operations on a [crate::system::System].
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::io;
+use std::ops::Bound;
 use std::path::PathBuf;
 
+use deb_version::compare_versions;
 use failure::err_msg;
+use failure::format_err;
 use failure::Error;
+use failure::ResultExt;
+use regex::Regex;
+use regex::RegexBuilder;
 
+use crate::cache::CachedVersion;
+use crate::cache::PackageCache;
 use crate::lists;
+use crate::policy::PackageFile;
+use crate::policy::Version;
 use crate::rfc822::RfcMapExt;
 use crate::sources_list;
 use crate::system::DownloadedList;
@@ -95,6 +106,213 @@ pub fn source_ninja(system: &System) -> Result<(), Error> {
     Ok(())
 }
 
+/// Print (to stdout) the installed and candidate versions of some packages, and every
+/// version, with its priority, and where it's available from, like `apt-cache policy`.
+///
+/// With no packages, print the priority of each _Listing_ instead.
+pub fn policy<S: AsRef<str>>(system: &System, names: &[S]) -> Result<(), Error> {
+    let policy = system.policy();
+
+    if names.is_empty() {
+        println!("Package files:");
+        for list in system.listings()? {
+            if "Packages" != list.listing.name {
+                continue;
+            }
+
+            let file = PackageFile::from_list(&list);
+            println!("{:>4} {}", policy.file_priority(&file), file);
+        }
+        return Ok(());
+    }
+
+    let version_or_none = |version: Option<&Version>| {
+        version.map_or("(none)".to_string(), |version| {
+            version.package.version.to_string()
+        })
+    };
+
+    let all = system.versions_of(names)?;
+    for name in names {
+        let name = name.as_ref();
+        let mut found = false;
+
+        // each architecture has its own candidate
+        for (qualified, versions) in by_arch(&all, name) {
+            found = true;
+            println!("{}:", qualified);
+            println!(
+                "  Installed: {}",
+                version_or_none(versions.iter().find(|version| version.is_installed()))
+            );
+            println!(
+                "  Candidate: {}",
+                version_or_none(policy.candidate(versions))
+            );
+            println!("  Version table:");
+
+            for version in versions {
+                let marker = if version.is_installed() { "***" } else { "   " };
+                println!(
+                    " {} {} {}",
+                    marker,
+                    version.package.version,
+                    policy.priority(version)
+                );
+                for file in &version.files {
+                    println!("        {:>4} {}", policy.file_priority(file), file);
+                }
+            }
+        }
+
+        if !found {
+            eprintln!("{}: not found", name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print (to stdout) every version of some packages, and where each is available from,
+/// like `apt-cache madison`. The installed version is from `a=now`.
+pub fn madison<S: AsRef<str>>(system: &System, names: &[S]) -> Result<(), Error> {
+    let all = system.versions_of(names)?;
+    for name in names {
+        let name = name.as_ref();
+        for (_, versions) in by_arch(&all, name) {
+            for version in versions {
+                for file in &version.files {
+                    println!("{:>10} | {:>10} | {}", name, version.package.version, file);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The versions of `name` from [System::versions_of], for each architecture: `name` itself,
+/// then each `name:arch`.
+fn by_arch<'a>(
+    all: &'a BTreeMap<String, Vec<Version>>,
+    name: &'a str,
+) -> impl Iterator<Item = (&'a String, &'a Vec<Version>)> {
+    all.range::<str, _>((Bound::Included(name), Bound::Unbounded))
+        .take_while(move |(qualified, _)| qualified.starts_with(name))
+        .filter(move |(qualified, _)| {
+            qualified.len() == name.len() || qualified[name.len()..].starts_with(':')
+        })
+}
+
+/// Print (to stdout) the name and short description of every binary package whose
+/// name or description matches all of the `patterns`, like `apt-cache search`.
+///
+/// The patterns are case-insensitive regexes. With `names_only`, descriptions are ignored.
+pub fn search<S: AsRef<str>>(
+    system: &System,
+    patterns: &[S],
+    names_only: bool,
+) -> Result<(), Error> {
+    let patterns = patterns
+        .iter()
+        .map(|pattern| {
+            let pattern = pattern.as_ref();
+            RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .with_context(|_| format_err!("invalid pattern: {:?}", pattern))
+        })
+        .collect::<Result<Vec<Regex>, _>>()?;
+
+    // many versions of a package will match; only list it once
+    let mut found = BTreeMap::new();
+
+    for block in all_blocks(system)? {
+        let block = block?;
+        let map = block.as_map()?;
+        if map.contains_key("Files") {
+            continue;
+        }
+
+        let name = map.get_value("Package").one_line_req()?;
+        if found.contains_key(name) {
+            continue;
+        }
+
+        let description: &[&str] = map.get("Description").map_or(&[], |lines| lines);
+        let matches = patterns.iter().all(|pattern| {
+            pattern.is_match(name)
+                || (!names_only && description.iter().any(|line| pattern.is_match(line)))
+        });
+
+        if matches {
+            let short = description.first().unwrap_or(&"");
+            found.insert(name.to_string(), short.to_string());
+        }
+    }
+
+    for (name, short) in found {
+        println!("{} - {}", name, short);
+    }
+
+    Ok(())
+}
+
+/// Print (to stdout) the whole _Block_ for every version of some binary packages, highest
+/// version first, like `apt-cache show`. The versions are found in the package cache.
+///
+/// Each package is a name, or `name=version` to only show that version.
+pub fn show<S: AsRef<str>>(system: &System, packages: &[S]) -> Result<(), Error> {
+    let cache = system.package_cache()?;
+
+    for package in packages {
+        let package = package.as_ref();
+        let (name, wanted) = match package.split_once('=') {
+            Some((name, version)) => (name, Some(version)),
+            None => (package, None),
+        };
+
+        let versions = find_versions(&cache, name, wanted);
+        if versions.is_empty() {
+            match wanted {
+                Some(version) => eprintln!("{}: version {} not found", name, version),
+                None => eprintln!("{}: not found", name),
+            }
+            continue;
+        }
+
+        for version in versions {
+            println!("{}", version.block().trim_end());
+            println!();
+        }
+    }
+
+    Ok(())
+}
+
+/// The distinct binary versions named `name`, highest first. With no `wanted` version,
+/// every version matches.
+fn find_versions<'c>(
+    cache: &'c PackageCache,
+    name: &str,
+    wanted: Option<&str>,
+) -> Vec<CachedVersion<'c>> {
+    let mut versions: Vec<CachedVersion> = Vec::new();
+    for version in cache.by_name(name) {
+        if version.is_source() || wanted.is_some_and(|v| v != version.version()) {
+            continue;
+        }
+
+        // the same version is often in many _Listings_
+        if !versions.iter().any(|seen| seen.block() == version.block()) {
+            versions.push(version);
+        }
+    }
+
+    versions.sort_by(|left, right| compare_versions(right.version(), left.version()));
+    versions
+}
+
 // Sigh, I've already written this.
 fn subdir(name: &str) -> &str {
     if name.starts_with("lib") {
@@ -182,3 +400,69 @@ fn print_ninja_binary(map: &HashMap<&str, Vec<&str>>) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::cache::Builder;
+    use crate::cache::PackageCache;
+
+    use super::by_arch;
+    use super::find_versions;
+
+    #[test]
+    fn arches_of_a_name() {
+        let all: BTreeMap<String, Vec<_>> = ["fo", "foo", "foo-bar", "foo:i386", "foobar"]
+            .iter()
+            .map(|name| (name.to_string(), Vec::new()))
+            .collect();
+
+        assert_eq!(
+            vec!["foo", "foo:i386"],
+            by_arch(&all, "foo")
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(0, by_arch(&all, "bar").count());
+    }
+
+    #[test]
+    fn show_versions() {
+        let mut builder = Builder::new();
+        for listing in &["aaaa", "bbbb"] {
+            builder.add_listing(listing).unwrap();
+            for block in &[
+                "Package: foo\nVersion: 1\n",
+                "Package: foo\nVersion: 2\n",
+                "Package: bar\nVersion: 1\n",
+            ] {
+                builder.add_block(block, false).unwrap();
+            }
+        }
+        builder
+            .add_block("Package: foo\nVersion: 3\nArchitecture: any\n", true)
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pkgcache.bin");
+        builder.write(&[0; 32], &path).unwrap();
+        let cache = PackageCache::open(&path).unwrap();
+
+        let blocks = |name: &str, wanted: Option<&str>| -> Vec<&str> {
+            find_versions(&cache, name, wanted)
+                .iter()
+                .map(|version| version.block().trim_end())
+                .collect()
+        };
+
+        assert_eq!(vec!["Package: foo\nVersion: 1"], blocks("foo", Some("1")));
+        assert_eq!(vec!["Package: foo\nVersion: 2"], blocks("foo", Some("2")));
+        assert_eq!(
+            vec!["Package: foo\nVersion: 2", "Package: foo\nVersion: 1"],
+            blocks("foo", None)
+        );
+        assert!(blocks("foo", Some("3")).is_empty());
+        assert_eq!(vec!["Package: bar\nVersion: 1"], blocks("bar", None));
+    }
+}
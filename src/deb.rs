@@ -0,0 +1,297 @@
+//! Reading `.deb` files: an `ar` archive of `debian-binary`, `control.tar.*` and `data.tar.*`.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str;
+
+use failure::bail;
+use failure::ensure;
+use failure::err_msg;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+use flate2::read::GzDecoder;
+
+use crate::lists::Compression;
+use crate::parse::Package;
+use crate::rfc822;
+
+const CONTROL_CODECS: [Compression; 4] = [
+    Compression::Gz,
+    Compression::Xz,
+    Compression::Zstd,
+    Compression::None,
+];
+
+const DATA_CODECS: [Compression; 5] = [
+    Compression::Gz,
+    Compression::Xz,
+    Compression::Zstd,
+    Compression::Bz2,
+    Compression::None,
+];
+
+/// The files from `control.tar`, e.g. `control`, `md5sums`, and the maintainer scripts.
+#[derive(Clone, Debug, Default)]
+pub struct Control {
+    /// The contents of each regular file, keyed by its name, e.g. `postinst`.
+    pub files: HashMap<String, Vec<u8>>,
+}
+
+impl Control {
+    /// The `control` file, parsed as if it was from a _Listing_.
+    pub fn package(&self) -> Result<Package, Error> {
+        let control = self
+            .files
+            .get("control")
+            .ok_or_else(|| err_msg("no control file in control.tar"))?;
+        let control = str::from_utf8(control).with_context(|_| err_msg("control file"))?;
+        let mut map = rfc822::fields_in_block(control).collect_to_map()?;
+        Package::parse(&mut map)
+    }
+}
+
+/// An item in `data.tar`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataEntry {
+    /// The path, relative to the root of the installation, e.g. `usr/bin/foo`.
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink(PathBuf),
+    HardLink(PathBuf),
+    Other,
+}
+
+/// Read the control members of a `.deb`.
+pub fn control<R: Read>(deb: R) -> Result<Control, Error> {
+    with_member(deb, "control.tar", &CONTROL_CODECS, |tar| {
+        let mut control = Control::default();
+        for entry in tar::Archive::new(tar).entries()? {
+            let mut entry = entry?;
+            if tar::EntryType::Regular != entry.header().entry_type() {
+                continue;
+            }
+
+            let name = entry.path()?.to_string_lossy().to_string();
+            let name = name.trim_start_matches("./").to_string();
+
+            let mut content = Vec::with_capacity(entry.size() as usize);
+            entry
+                .read_to_end(&mut content)
+                .with_context(|_| format_err!("reading {:?}", name))?;
+            control.files.insert(name, content);
+        }
+        Ok(control)
+    })
+}
+
+/// List the files a `.deb` would install, in archive order.
+pub fn list<R: Read>(deb: R) -> Result<Vec<DataEntry>, Error> {
+    with_member(deb, "data.tar", &DATA_CODECS, |tar| {
+        let mut ret = Vec::new();
+        for entry in tar::Archive::new(tar).entries()? {
+            let entry = entry?;
+            let header = entry.header();
+            let link = || -> Result<PathBuf, Error> {
+                Ok(entry
+                    .link_name()?
+                    .ok_or_else(|| err_msg("link without a target"))?
+                    .into_owned())
+            };
+
+            let kind = match header.entry_type() {
+                tar::EntryType::Regular => EntryKind::File,
+                tar::EntryType::Directory => EntryKind::Directory,
+                tar::EntryType::Symlink => EntryKind::Symlink(link()?),
+                tar::EntryType::Link => EntryKind::HardLink(link()?),
+                _ => EntryKind::Other,
+            };
+
+            ret.push(DataEntry {
+                path: entry.path()?.into_owned(),
+                kind,
+                size: entry.size(),
+                mode: header.mode()?,
+            });
+        }
+        Ok(ret)
+    })
+}
+
+/// Extract the files a `.deb` would install into `dest`, which is created if necessary.
+///
+/// Nothing is written outside of `dest`, and maintainer scripts are not run.
+pub fn unpack<R: Read, P: AsRef<Path>>(deb: R, dest: P) -> Result<(), Error> {
+    let dest = dest.as_ref();
+    with_member(deb, "data.tar", &DATA_CODECS, |tar| {
+        tar::Archive::new(tar)
+            .unpack(dest)
+            .with_context(|_| format_err!("unpacking into {:?}", dest))?;
+        Ok(())
+    })
+}
+
+/// Find the member named `prefix` plus a compression suffix, and feed its decompressed content to `f`.
+fn with_member<R: Read, T, F>(
+    deb: R,
+    prefix: &str,
+    codecs: &[Compression],
+    f: F,
+) -> Result<T, Error>
+where
+    F: FnOnce(&mut dyn Read) -> Result<T, Error>,
+{
+    let mut archive = ar::Archive::new(deb);
+
+    let mut version = String::new();
+    archive
+        .next_entry()
+        .ok_or_else(|| err_msg("empty archive"))??
+        .take(16)
+        .read_to_string(&mut version)
+        .with_context(|_| err_msg("reading debian-binary"))?;
+    ensure!(
+        version.starts_with("2."),
+        "unsupported package format version: {:?}",
+        version
+    );
+
+    while let Some(entry) = archive.next_entry() {
+        let entry = entry?;
+
+        // GNU ar terminates names with a slash
+        let name = str::from_utf8(entry.header().identifier())?
+            .trim_end_matches('/')
+            .to_string();
+
+        let codec = match codecs
+            .iter()
+            .find(|codec| name == format!("{}{}", prefix, codec.suffix()))
+        {
+            Some(codec) => *codec,
+            None => continue,
+        };
+
+        let mut reader = decoder(codec, entry).with_context(|_| format_err!("reading {}", name))?;
+        return Ok(f(&mut *reader).with_context(|_| format_err!("processing {}", name))?);
+    }
+
+    bail!("no supported {} in package", prefix)
+}
+
+fn decoder<'r, R: Read + 'r>(codec: Compression, from: R) -> Result<Box<dyn Read + 'r>, Error> {
+    let from = io::BufReader::new(from);
+    Ok(match codec {
+        Compression::None => Box::new(from),
+        Compression::Gz => Box::new(GzDecoder::new(from)),
+        Compression::Bz2 => Box::new(bzip2_rs::DecoderReader::new(from)),
+        Compression::Zstd => Box::new(
+            ruzstd::decoding::StreamingDecoder::new(from).map_err(|e| format_err!("{}", e))?,
+        ),
+        Compression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(from)),
+        Compression::Xz => {
+            // there's no streaming xz decoder, and data.tar can be large
+            let mut temp = tempfile::tempfile()?;
+            lzma_rs::xz_decompress(&mut { from }, &mut temp).map_err(|e| format_err!("{}", e))?;
+            temp.seek(SeekFrom::Start(0))?;
+            Box::new(temp)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    use super::EntryKind;
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, *content).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    fn deb() -> Vec<u8> {
+        let control = tar(&[
+            (
+                "./control",
+                b"Package: foo\nVersion: 1.0-1\nArchitecture: all\nSection: misc\n\
+                  Maintainer: Foo <foo@example.com>\nDescription: foo\n"
+                    .as_ref(),
+            ),
+            ("./postinst", b"#!/bin/sh\n".as_ref()),
+        ]);
+        let mut control_gz =
+            flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        io::copy(&mut control.as_slice(), &mut control_gz).unwrap();
+
+        let data = tar(&[("./usr/share/foo/bar", b"hello\n".as_ref())]);
+        let mut data_xz = Vec::new();
+        lzma_rs::xz_compress(&mut data.as_slice(), &mut data_xz).unwrap();
+
+        let mut ar = ar::Builder::new(Vec::new());
+        let members: [(&str, &[u8]); 3] = [
+            ("debian-binary", b"2.0\n"),
+            ("control.tar.gz", &control_gz.finish().unwrap()),
+            ("data.tar.xz", &data_xz),
+        ];
+        for (name, content) in &members {
+            ar.append(
+                &ar::Header::new(name.as_bytes().to_vec(), content.len() as u64),
+                *content,
+            )
+            .unwrap();
+        }
+        ar.into_inner().unwrap()
+    }
+
+    #[test]
+    fn read() {
+        let deb = deb();
+
+        let control = super::control(deb.as_slice()).unwrap();
+        assert_eq!(
+            b"#!/bin/sh\n".as_ref(),
+            control.files["postinst"].as_slice()
+        );
+        let package = control.package().unwrap();
+        assert_eq!("foo", package.name);
+        assert_eq!("1.0-1", package.version);
+
+        let list = super::list(deb.as_slice()).unwrap();
+        assert_eq!(1, list.len());
+        assert_eq!(PathBuf::from("usr/share/foo/bar"), list[0].path);
+        assert_eq!(EntryKind::File, list[0].kind);
+        assert_eq!(6, list[0].size);
+
+        let dir = tempfile::tempdir().unwrap();
+        super::unpack(deb.as_slice(), dir.path()).unwrap();
+        assert_eq!(
+            "hello\n",
+            fs::read_to_string(dir.path().join("usr/share/foo/bar")).unwrap()
+        );
+
+        assert!(super::list(&b"!<arch>\n"[..]).is_err());
+    }
+}
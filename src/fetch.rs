@@ -1,72 +1,261 @@
+//! Retrieving files from urls, e.g. over HTTP.
+//!
+//! The network access is done by a [Transport], which can be replaced, e.g. to add
+//! authentication, or to serve responses from memory in tests.
+//!
+//! Nothing is printed; a [Progress] implementation can be used to observe downloads.
+
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::io;
+use std::io::Read;
 use std::io::Write;
+use std::iter;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::vec;
 
 use chrono::DateTime;
 use chrono::Utc;
 use failure::bail;
+use failure::ensure;
 use failure::format_err;
 use failure::Error;
+use failure::Fail;
 use failure::ResultExt;
-use filetime;
-use reqwest;
 use reqwest::header;
+use reqwest::Url;
 use tempfile_fast::PersistableTempFile;
 
+use crate::checksum;
+use crate::checksum::HashPolicy;
+use crate::checksum::Hashes;
+
+/// A request to store the contents of a url at a local path.
 pub struct Download {
-    from: reqwest::Url,
-    to: PathBuf,
+    pub from: Url,
+    pub to: PathBuf,
+    /// The same file on other mirrors, to try in order if `from` fails.
+    pub mirrors: Vec<Url>,
+    /// If present, a download which doesn't match is treated like a failure of that mirror.
+    pub(crate) hashes: Option<Hashes>,
 }
 
 impl Download {
-    pub fn from_to<P: AsRef<Path>>(from: reqwest::Url, to: P) -> Self {
+    pub fn from_to<P: AsRef<Path>>(from: Url, to: P) -> Self {
         Download {
             from,
             to: to.as_ref().to_path_buf(),
+            mirrors: Vec::new(),
+            hashes: None,
         }
     }
 }
 
-pub fn fetch(client: &reqwest::Client, downloads: &[Download]) -> Result<(), Error> {
-    // TODO: reqwest parallel API, when it's stable
+/// A failure which will not be fixed by retrying, e.g. `404 Not Found`.
+///
+/// Transports should return this (e.g. via. `Error::from`), so the download can move
+/// on to the next mirror immediately. Any other error is considered transient.
+#[derive(Debug)]
+pub struct PermanentFailure {
+    pub url: Url,
+    pub reason: String,
+}
 
-    for download in downloads {
-        write!(io::stderr(), "Downloading: {} ... ", download.from)?;
-        io::stderr().flush()?;
-        fetch_single(client, download)
-            .with_context(|_| format_err!("downloading {} to {:?}", download.from, download.to))?;
+impl fmt::Display for PermanentFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "couldn't download {}: {}", self.url, self.reason)
     }
+}
 
-    Ok(())
+impl Fail for PermanentFailure {}
+
+/// The outcome of a successful [Transport::fetch].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fetched {
+    /// The file was retrieved, and has been stored.
+    Downloaded,
+    /// The file hasn't changed since `if_modified_since`, and has been left alone.
+    NotModified,
 }
 
-fn fetch_single(client: &reqwest::Client, download: &Download) -> Result<(), Error> {
-    let mut req = client.get(download.from.as_ref());
+/// A way to retrieve the contents of urls, e.g. over HTTP.
+///
+/// `reqwest::Client` is the default implementation. `file:` and `copy:` urls are always
+/// read directly from the filesystem, and are never passed to the `Transport`.
+pub trait Transport: Send + Sync {
+    /// Retrieve `url`, and store it at `to`, e.g. using [persist].
+    ///
+    /// If `if_modified_since` is present, `to` already exists, and was last modified
+    /// at this time. If the remote file is no newer, the implementation may leave `to`
+    /// alone, and return `Fetched::NotModified`.
+    ///
+    /// The implementation should report the length and data it receives to `progress`,
+    /// e.g. with a [ProgressReader].
+    fn fetch(
+        &self,
+        url: &Url,
+        to: &Path,
+        if_modified_since: Option<SystemTime>,
+        progress: &dyn Progress,
+    ) -> Result<Fetched, Error>;
+}
+
+/// Notifications about downloads, e.g. to drive a progress bar, or for logging.
+///
+/// Downloads happen in parallel, so these may be called from multiple threads at once.
+/// Every method does nothing by default.
+#[allow(unused_variables)]
+pub trait Progress: Send + Sync {
+    /// An attempt to download `url` is starting.
+    fn started(&self, url: &Url) {}
+
+    /// The server has told us how long `url` is.
+    fn total_length(&self, url: &Url, len: u64) {}
+
+    /// Some more data for `url` has arrived; `bytes` is the amount in this chunk.
+    fn received(&self, url: &Url, bytes: u64) {}
+
+    /// Our copy of `url` is already current, so nothing was downloaded.
+    fn up_to_date(&self, url: &Url) {}
 
-    if download.to.exists() {
-        let when: DateTime<Utc> = ::chrono::DateTime::from(download.to.metadata()?.modified()?);
-        req = req.header(header::IF_MODIFIED_SINCE, when.to_rfc2822());
+    /// `url` has been downloaded.
+    fn finished(&self, url: &Url) {}
+
+    /// An attempt to download `url` failed. It may yet be retried, or tried on another mirror.
+    fn failed(&self, url: &Url, error: &Error) {}
+
+    /// The contents of `url` have been checked against their expected hashes or signatures.
+    fn verified(&self, url: &Url) {}
+}
+
+/// Report nothing.
+impl Progress for () {}
+
+/// Print a line to `stderr` as each download completes, as the `fapt` tool does.
+pub struct StderrProgress;
+
+impl Progress for StderrProgress {
+    fn up_to_date(&self, url: &Url) {
+        // a single write, so parallel downloads don't interleave their output
+        let _ = writeln!(io::stderr(), "Downloading: {} ... already up to date.", url);
     }
 
-    let mut resp = req
-        .send()
-        .with_context(|_| format_err!("initiating request"))?;
+    fn finished(&self, url: &Url) {
+        let _ = writeln!(io::stderr(), "Downloading: {} ... complete.", url);
+    }
+}
 
-    let status = resp.status();
-    if reqwest::StatusCode::NOT_MODIFIED == status {
-        writeln!(io::stderr(), "already up to date.")?;
-        return Ok(());
-    } else if !status.is_success() {
-        bail!(
-            "couldn't download {}: server responded with {:?}",
-            download.from,
-            status
-        );
+/// Report data to a [Progress] as it is read.
+pub struct ProgressReader<'p, R> {
+    inner: R,
+    url: &'p Url,
+    progress: &'p dyn Progress,
+}
+
+impl<'p, R: Read> ProgressReader<'p, R> {
+    pub fn new(inner: R, url: &'p Url, progress: &'p dyn Progress) -> Self {
+        ProgressReader {
+            inner,
+            url,
+            progress,
+        }
+    }
+}
+
+impl<'p, R: Read> Read for ProgressReader<'p, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if 0 != read {
+            self.progress.received(self.url, read as u64);
+        }
+        Ok(read)
     }
-    let parent = download
-        .to
+}
+
+impl Transport for reqwest::Client {
+    fn fetch(
+        &self,
+        url: &Url,
+        to: &Path,
+        if_modified_since: Option<SystemTime>,
+        progress: &dyn Progress,
+    ) -> Result<Fetched, Error> {
+        let mut req = self.get(url.as_ref());
+
+        if let Some(when) = if_modified_since {
+            let when: DateTime<Utc> = DateTime::from(when);
+            req = req.header(header::IF_MODIFIED_SINCE, when.to_rfc2822());
+        }
+
+        let mut resp = req
+            .send()
+            .with_context(|_| format_err!("initiating request"))?;
+
+        let status = resp.status();
+        if reqwest::StatusCode::NOT_MODIFIED == status {
+            return Ok(Fetched::NotModified);
+        } else if status.is_client_error()
+            && reqwest::StatusCode::REQUEST_TIMEOUT != status
+            && reqwest::StatusCode::TOO_MANY_REQUESTS != status
+        {
+            return Err(PermanentFailure {
+                url: url.clone(),
+                reason: format!("server responded with {:?}", status),
+            }
+            .into());
+        } else if !status.is_success() {
+            bail!(
+                "couldn't download {}: server responded with {:?}",
+                url,
+                status
+            );
+        }
+
+        let len = match resp.headers().get(header::CONTENT_LENGTH) {
+            Some(len) => Some(len.to_str()?.parse()?),
+            None => None,
+        };
+
+        if let Some(len) = len {
+            progress.total_length(url, len);
+        }
+
+        let modified = match resp.headers().get(header::LAST_MODIFIED) {
+            Some(modified) => Some(SystemTime::from(DateTime::parse_from_rfc2822(
+                modified.to_str()?,
+            )?)),
+            None => None,
+        };
+
+        persist(
+            ProgressReader::new(&mut resp, url, progress),
+            to,
+            len,
+            modified,
+        )?;
+
+        Ok(Fetched::Downloaded)
+    }
+}
+
+/// Atomically store the data from a reader at `to`, via. a temporary file in the same directory.
+///
+/// `len` is the expected length; if the reader provides a different amount of data (e.g.
+/// the connection was closed early), nothing is stored. `modified` is stored as the file's
+/// mtime, for use as the next `if_modified_since`.
+pub fn persist<R: Read>(
+    mut from: R,
+    to: &Path,
+    len: Option<u64>,
+    modified: Option<SystemTime>,
+) -> Result<(), Error> {
+    let parent = to
         .parent()
         .ok_or_else(|| format_err!("path must have parent"))?;
 
@@ -76,24 +265,533 @@ fn fetch_single(client: &reqwest::Client, download: &Download) -> Result<(), Err
     let mut tmp = PersistableTempFile::new_in(parent)
         .with_context(|_| format_err!("couldn't create temporary file"))?;
 
-    if let Some(len) = resp.headers().get(header::CONTENT_LENGTH) {
-        tmp.set_len(len.to_str()?.parse()?)
+    if let Some(len) = len {
+        tmp.set_len(len)
             .with_context(|_| format_err!("pretending to allocate space"))?;
     }
 
-    io::copy(&mut resp, &mut tmp).with_context(|_| format_err!("copying data"))?;
+    let copied = io::copy(&mut from, &mut tmp).with_context(|_| format_err!("copying data"))?;
+
+    if let Some(len) = len {
+        ensure!(
+            copied == len,
+            "truncated download: expected {} bytes, got {}",
+            len,
+            copied
+        );
+    }
 
-    tmp.persist_by_rename(&download.to)
+    tmp.persist_by_rename(to)
         .map_err(|e| e.error)
         .with_context(|_| format_err!("persisting result"))?;
 
-    if let Some(modified) = resp.headers().get(header::LAST_MODIFIED) {
-        let date = DateTime::parse_from_rfc2822(modified.to_str()?)?;
-        let file_time = filetime::FileTime::from_unix_time(date.timestamp(), 0);
-        filetime::set_file_times(&download.to, file_time, file_time)?;
+    if let Some(modified) = modified {
+        let file_time = filetime::FileTime::from_system_time(modified);
+        filetime::set_file_times(to, file_time, file_time)?;
+    }
+
+    Ok(())
+}
+
+/// The [Transport], and the settings for how to use it.
+pub(crate) struct Fetcher {
+    pub(crate) transport: Box<dyn Transport>,
+    pub(crate) progress: Box<dyn Progress>,
+    pub(crate) connections_per_host: usize,
+    /// How many times to retry a transient failure, before moving on to the next mirror.
+    pub(crate) retries: u32,
+    /// The delay before the first retry, which doubles for each subsequent retry.
+    pub(crate) backoff: Duration,
+    /// Which hashes are good enough to accept a download.
+    pub(crate) hash_policy: HashPolicy,
+}
+
+impl Fetcher {
+    pub(crate) fn new(transport: Box<dyn Transport>) -> Fetcher {
+        Fetcher {
+            transport,
+            progress: Box::new(()),
+            connections_per_host: 4,
+            retries: 3,
+            backoff: Duration::from_secs(1),
+            hash_policy: HashPolicy::default(),
+        }
+    }
+
+    /// Perform all of the downloads, with up to `connections_per_host` in progress
+    /// for each host at once.
+    ///
+    /// A failed download doesn't stop the others; all the failures are reported at the end.
+    pub(crate) fn fetch(&self, downloads: &[Download]) -> Result<(), Error> {
+        let mut by_host: HashMap<(Option<&str>, Option<u16>), Vec<&Download>> = HashMap::new();
+        for download in downloads {
+            by_host
+                .entry((
+                    download.from.host_str(),
+                    download.from.port_or_known_default(),
+                ))
+                .or_default()
+                .push(download);
+        }
+
+        let queues: Vec<(usize, Mutex<vec::IntoIter<&Download>>)> = by_host
+            .into_values()
+            .map(|downloads| (downloads.len(), Mutex::new(downloads.into_iter())))
+            .collect();
+
+        let failures = Mutex::new(Vec::new());
+        let failures_ref = &failures;
+
+        thread::scope(|scope| {
+            for (len, queue) in &queues {
+                for _ in 0..self.connections_per_host.max(1).min(*len) {
+                    scope.spawn(move || loop {
+                        let download = match queue.lock().expect("poisoned").next() {
+                            Some(download) => download,
+                            None => break,
+                        };
+
+                        if let Err(e) = self.fetch_mirrors(download) {
+                            failures_ref.lock().expect("poisoned").push(
+                                e.context(format!(
+                                    "downloading {} to {:?}",
+                                    download.from, download.to
+                                ))
+                                .into(),
+                            );
+                        }
+                    });
+                }
+            }
+        });
+
+        let mut failures = failures.into_inner().expect("poisoned");
+
+        match failures.len() {
+            0 => Ok(()),
+            1 => Err(failures.remove(0)),
+            count => bail!(
+                "{} downloads failed: {}",
+                count,
+                failures
+                    .iter()
+                    .map(|e| e
+                        .iter_chain()
+                        .map(|cause| cause.to_string())
+                        .collect::<Vec<String>>()
+                        .join(": "))
+                    .collect::<Vec<String>>()
+                    .join("; ")
+            ),
+        }
     }
+}
+
+impl Fetcher {
+    /// Try each mirror in turn, retrying transient failures, until one succeeds.
+    fn fetch_mirrors(&self, download: &Download) -> Result<Fetched, Error> {
+        if let Some(hashes) = &download.hashes {
+            self.hash_policy.check(hashes)?;
+        }
+
+        let mut last_error = None;
+
+        for url in iter::once(&download.from).chain(&download.mirrors) {
+            match self.fetch_retrying(url, download) {
+                Ok(fetched) => return Ok(fetched),
+                Err(e) => last_error = Some(e.context(format!("trying {}", url)).into()),
+            }
+        }
+
+        Err(last_error.expect("there's always at least one url"))
+    }
+
+    fn fetch_retrying(&self, url: &Url, download: &Download) -> Result<Fetched, Error> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+
+        loop {
+            self.progress.started(url);
 
-    writeln!(io::stderr(), "complete.")?;
+            let err = match self.fetch_validated(url, download) {
+                Ok(fetched) => return Ok(fetched),
+                Err(e) => e,
+            };
+
+            self.progress.failed(url, &err);
+
+            if attempt >= self.retries || err.downcast_ref::<PermanentFailure>().is_some() {
+                return Err(err);
+            }
+
+            attempt += 1;
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+}
+
+impl Fetcher {
+    fn fetch_validated(&self, url: &Url, download: &Download) -> Result<Fetched, Error> {
+        let fetched = fetch_single(
+            self.transport.as_ref(),
+            url,
+            &download.to,
+            self.progress.as_ref(),
+        )?;
+
+        if let Some(hashes) = download.hashes {
+            validate(download, url, hashes)?;
+            self.progress.verified(url);
+        }
+
+        match fetched {
+            Fetched::Downloaded => self.progress.finished(url),
+            Fetched::NotModified => self.progress.up_to_date(url),
+        }
+
+        Ok(fetched)
+    }
+}
+
+/// Discard the download if it doesn't match, so the next mirror can't consider it up to date.
+///
+/// A mismatch is a [PermanentFailure]: the mirror would serve the same content again.
+fn validate(download: &Download, url: &Url, hashes: Hashes) -> Result<(), Error> {
+    let result = fs::File::open(&download.to)
+        .map_err(Error::from)
+        .and_then(|file| checksum::validate(file, hashes));
+
+    if let Err(e) = result {
+        fs::remove_file(&download.to)
+            .with_context(|_| format_err!("removing invalid download {:?}", download.to))?;
+        return Err(PermanentFailure {
+            url: url.clone(),
+            reason: e.to_string(),
+        }
+        .into());
+    }
 
     Ok(())
 }
+
+fn fetch_single(
+    transport: &dyn Transport,
+    url: &Url,
+    to: &Path,
+    progress: &dyn Progress,
+) -> Result<Fetched, Error> {
+    let if_modified_since = if to.exists() {
+        Some(to.metadata()?.modified()?)
+    } else {
+        None
+    };
+
+    match url.scheme() {
+        "file" | "copy" => fetch_local(url, to, if_modified_since, progress),
+        _ => transport.fetch(url, to, if_modified_since, progress),
+    }
+}
+
+/// "Download" from the local filesystem, treating the source's mtime as `Last-Modified`.
+fn fetch_local(
+    url: &Url,
+    to: &Path,
+    if_modified_since: Option<SystemTime>,
+    progress: &dyn Progress,
+) -> Result<Fetched, Error> {
+    // `copy:` urls are identical to `file:` urls, but only `file:` urls can be converted
+    let after_scheme = &url.as_str()[url.scheme().len()..];
+    let source = Url::parse(&format!("file{}", after_scheme))?
+        .to_file_path()
+        .map_err(|()| format_err!("not a local path: {}", url))?;
+
+    let metadata = match fs::metadata(&source) {
+        Ok(metadata) => metadata,
+        // like a `404`, e.g. a repository without an `InRelease`
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => {
+            return Err(PermanentFailure {
+                url: url.clone(),
+                reason: format!("{:?} not found", source),
+            }
+            .into());
+        }
+        Err(e) => return Err(e.context(format_err!("finding {:?}", source)).into()),
+    };
+    let modified = metadata.modified()?;
+
+    if let Some(if_modified_since) = if_modified_since {
+        if modified <= if_modified_since {
+            return Ok(Fetched::NotModified);
+        }
+    }
+
+    progress.total_length(url, metadata.len());
+
+    persist(
+        ProgressReader::new(
+            fs::File::open(&source).with_context(|_| format_err!("opening {:?}", source))?,
+            url,
+            progress,
+        ),
+        to,
+        Some(metadata.len()),
+        Some(modified),
+    )?;
+
+    Ok(Fetched::Downloaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    use failure::bail;
+    use failure::Error;
+    use reqwest::Url;
+    use sha2::Digest;
+    use sha2::Sha256;
+
+    use super::fetch_single;
+    use super::persist;
+    use super::Download;
+    use super::Fetched;
+    use super::Fetcher;
+    use super::Hashes;
+    use super::PermanentFailure;
+    use super::Progress;
+    use super::Transport;
+
+    #[test]
+    fn local() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let dest = dir.path().join("lists").join("dest");
+        fs::write(&source, b"hello").unwrap();
+
+        for scheme in &["file", "copy"] {
+            let url = Url::parse(&format!("{}://{}", scheme, source.display())).unwrap();
+
+            let client = reqwest::Client::new();
+            let fetch = |url: &Url| fetch_single(&client, url, &dest, &());
+
+            assert_eq!(Fetched::Downloaded, fetch(&url).unwrap());
+            assert_eq!(b"hello", fs::read(&dest).unwrap().as_slice());
+
+            // not modified, so not copied again
+            let mtime =
+                filetime::FileTime::from_last_modification_time(&fs::metadata(&dest).unwrap());
+            fs::write(&dest, b"stale").unwrap();
+            filetime::set_file_mtime(&dest, mtime).unwrap();
+            assert_eq!(Fetched::NotModified, fetch(&url).unwrap());
+            assert_eq!(b"stale", fs::read(&dest).unwrap().as_slice());
+
+            // older than the source, so replaced
+            filetime::set_file_mtime(&dest, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+            assert_eq!(Fetched::Downloaded, fetch(&url).unwrap());
+            assert_eq!(b"hello", fs::read(&dest).unwrap().as_slice());
+
+            fs::remove_file(&dest).unwrap();
+        }
+
+        // not worth retrying
+        let missing = Url::from_file_path(dir.path().join("missing")).unwrap();
+        assert!(fetch_single(&reqwest::Client::new(), &missing, &dest, &())
+            .unwrap_err()
+            .downcast_ref::<PermanentFailure>()
+            .is_some());
+    }
+
+    struct Fake;
+
+    impl Transport for Fake {
+        fn fetch(
+            &self,
+            url: &Url,
+            to: &Path,
+            if_modified_since: Option<SystemTime>,
+            _progress: &dyn Progress,
+        ) -> Result<Fetched, Error> {
+            if if_modified_since.is_some() {
+                return Ok(Fetched::NotModified);
+            }
+            if url.path().contains("fail") {
+                bail!("refusing to serve {}", url);
+            }
+            persist(io::Cursor::new(url.as_str()), to, None, None)?;
+            Ok(Fetched::Downloaded)
+        }
+    }
+
+    #[test]
+    fn custom_transport() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("dest");
+        let url = Url::parse("https://example.com/foo").unwrap();
+
+        assert_eq!(
+            Fetched::Downloaded,
+            fetch_single(&Fake, &url, &dest, &()).unwrap()
+        );
+        assert_eq!(
+            b"https://example.com/foo",
+            fs::read(&dest).unwrap().as_slice()
+        );
+        assert_eq!(
+            Fetched::NotModified,
+            fetch_single(&Fake, &url, &dest, &()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parallel_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let downloads: Vec<Download> = ["a", "fail-b", "c", "d", "fail-e", "f"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let host = if 0 == i % 2 { "one" } else { "two" };
+                Download::from_to(
+                    Url::parse(&format!("https://{}.example.com/{}", host, name)).unwrap(),
+                    dir.path().join(name),
+                )
+            })
+            .collect();
+
+        let mut fetcher = Fetcher::new(Box::new(Fake));
+        fetcher.connections_per_host = 2;
+        fetcher.retries = 0;
+        let err = fetcher.fetch(&downloads).unwrap_err().to_string();
+        assert!(err.starts_with("2 downloads failed"), "{}", err);
+
+        for name in &["a", "c", "d", "f"] {
+            assert!(dir.path().join(name).exists(), "{}", name);
+        }
+        for name in &["fail-b", "fail-e"] {
+            assert!(!dir.path().join(name).exists(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn mirror_failover() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("dest");
+        let good = "https://three.example.com/foo";
+
+        let mut download =
+            Download::from_to(Url::parse("https://one.example.com/fail").unwrap(), &dest);
+        download.mirrors = vec![
+            Url::parse("https://two.example.com/foo").unwrap(),
+            Url::parse(good).unwrap(),
+        ];
+        download.hashes = Some(Hashes {
+            sha256: Some(Sha256::digest(good.as_bytes()).into()),
+            ..Hashes::default()
+        });
+
+        let mut fetcher = Fetcher::new(Box::new(Fake));
+        fetcher.backoff = Duration::from_millis(1);
+        fetcher.fetch(&[download]).unwrap();
+        assert_eq!(good.as_bytes(), fs::read(&dest).unwrap().as_slice());
+    }
+
+    /// The [Fake], remembering which urls were requested.
+    struct Recording(Arc<Mutex<Vec<String>>>);
+
+    impl Transport for Recording {
+        fn fetch(
+            &self,
+            url: &Url,
+            to: &Path,
+            if_modified_since: Option<SystemTime>,
+            progress: &dyn Progress,
+        ) -> Result<Fetched, Error> {
+            self.0.lock().unwrap().push(url.to_string());
+            Fake.fetch(url, to, if_modified_since, progress)
+        }
+    }
+
+    #[derive(Default, Clone)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        fn record(&self, event: &str, url: &Url) {
+            let host = url.host_str().unwrap().split('.').next().unwrap();
+            self.0.lock().unwrap().push(format!("{} {}", event, host));
+        }
+    }
+
+    impl Progress for Recorder {
+        fn started(&self, url: &Url) {
+            self.record("started", url)
+        }
+
+        fn failed(&self, url: &Url, _error: &Error) {
+            self.record("failed", url)
+        }
+
+        fn verified(&self, url: &Url) {
+            self.record("verified", url)
+        }
+
+        fn finished(&self, url: &Url) {
+            self.record("finished", url)
+        }
+    }
+
+    #[test]
+    fn hash_mismatch_fails_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("dest");
+        let bad = "https://one.example.com/foo";
+        let good = "https://two.example.com/foo";
+
+        let mut download = Download::from_to(Url::parse(bad).unwrap(), &dest);
+        download.mirrors = vec![Url::parse(good).unwrap()];
+        download.hashes = Some(Hashes {
+            sha256: Some(Sha256::digest(good.as_bytes()).into()),
+            ..Hashes::default()
+        });
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let fetcher = Fetcher::new(Box::new(Recording(requests.clone())));
+        fetcher.fetch(&[download]).unwrap();
+
+        // not retried, even with the default retries
+        assert_eq!(vec![bad, good], *requests.lock().unwrap());
+        assert_eq!(good.as_bytes(), fs::read(&dest).unwrap().as_slice());
+    }
+
+    #[test]
+    fn progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = Url::parse("https://one.example.com/fail").unwrap();
+        let mut download = Download::from_to(url, dir.path().join("dest"));
+        download.mirrors = vec![Url::parse("https://two.example.com/foo").unwrap()];
+
+        let recorder = Recorder::default();
+        let mut fetcher = Fetcher::new(Box::new(Fake));
+        fetcher.backoff = Duration::from_millis(1);
+        fetcher.retries = 1;
+        fetcher.progress = Box::new(recorder.clone());
+        fetcher.fetch(&[download]).unwrap();
+
+        assert_eq!(
+            vec![
+                "started one",
+                "failed one",
+                "started one",
+                "failed one",
+                "started two",
+                "finished two"
+            ],
+            *recorder.0.lock().unwrap()
+        );
+    }
+}
@@ -5,12 +5,21 @@
 #[macro_use]
 extern crate nom;
 
-mod checksum;
+mod archives;
+pub mod build_dep;
+pub mod cache;
+pub mod checksum;
 pub mod commands;
-mod fetch;
+pub mod deb;
+pub mod fetch;
 mod lists;
 pub mod parse;
-mod release;
+mod pdiff;
+pub mod policy;
+pub mod preferences;
+pub mod rdepends;
+pub mod release;
+pub mod resolve;
 pub mod rfc822;
 mod signing;
 pub mod sources_list;
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::io::Read;
@@ -5,35 +6,57 @@ use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
 use std::path::Path;
+use std::path::PathBuf;
 
 use crate::rfc822;
+use failure::ensure;
 use failure::format_err;
 use failure::Error;
 use failure::ResultExt;
 use flate2::bufread::GzDecoder;
 use hex;
-use reqwest::Client;
 use reqwest::Url;
 use tempfile_fast::PersistableTempFile;
 
 use crate::checksum;
 use crate::checksum::Hashes;
 use crate::fetch;
+use crate::pdiff;
+use crate::pdiff::IndexEntry;
 use crate::release::Release;
 use crate::release::ReleaseContent;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Compression {
     None,
     Gz,
+    Xz,
+    Bz2,
+    Zstd,
+    Lz4,
 }
 
 impl Compression {
-    fn suffix(&self) -> &'static str {
+    /// The variants to download, if available, most preferred first:
+    /// the smallest downloads, then the fastest to decompress.
+    pub const DEFAULT_ORDER: [Compression; 6] = [
+        Compression::Xz,
+        Compression::Zstd,
+        Compression::Bz2,
+        Compression::Gz,
+        Compression::Lz4,
+        Compression::None,
+    ];
+
+    pub(crate) fn suffix(&self) -> &'static str {
         use self::Compression::*;
         match *self {
             None => "",
             Gz => ".gz",
+            Xz => ".xz",
+            Bz2 => ".bz2",
+            Zstd => ".zst",
+            Lz4 => ".lz4",
         }
     }
 }
@@ -41,22 +64,67 @@ impl Compression {
 #[derive(Debug)]
 pub struct DownloadableListing {
     pub url: Url,
+    /// The same file on other mirrors of the release.
+    pub mirrors: Vec<Url>,
     pub codec: Compression,
     pub compressed_hashes: Hashes,
     pub decompressed_hashes: Hashes,
+    /// Patches to update an older copy, if the release provides them.
+    pub diffs: Option<Diffs>,
 }
 
 impl DownloadableListing {
     pub fn local_name(&self) -> String {
-        hex::encode(self.decompressed_hashes.sha256)
+        hex::encode(
+            self.decompressed_hashes
+                .sha256
+                .expect("find_file requires a sha256"),
+        )
+    }
+}
+
+/// The `Packages.diff/` directory next to a _Listing_, containing an `Index` of patches.
+#[derive(Debug)]
+pub struct Diffs {
+    pub dir: Url,
+    /// The same directory on other mirrors of the release.
+    pub mirrors: Vec<Url>,
+    pub index_hashes: Hashes,
+}
+
+impl Diffs {
+    fn download<P: AsRef<Path>>(
+        &self,
+        name: &str,
+        to: P,
+        hashes: Hashes,
+    ) -> Result<fetch::Download, Error> {
+        let mut download = fetch::Download::from_to(self.dir.join(name)?, to);
+        download.mirrors = self
+            .mirrors
+            .iter()
+            .map(|mirror| mirror.join(name))
+            .collect::<Result<_, _>>()?;
+        download.hashes = Some(hashes);
+        Ok(download)
     }
 }
 
+/// A _Listing_ we can bring up to date by patching an older copy.
+struct Patching<'l> {
+    list: &'l DownloadableListing,
+    old: PathBuf,
+    /// The downloaded (compressed) patches, and the hashes of their decompressed content.
+    patches: Vec<(PathBuf, Hashes)>,
+}
+
 // https://deb.debian.org/debian/dists/unstable/contrib/binary-amd64/Packages.gz
 // arch: Some("amd64"),
 // component: "contrib",
 // directory: "binary",
 // name: "packages"
+//
+// For flat repositories, the component and directory are empty, and there's no arch.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Listing {
     pub component: String,
@@ -66,34 +134,46 @@ pub struct Listing {
 }
 
 pub fn download_files<P: AsRef<Path>>(
-    client: &Client,
+    fetcher: &fetch::Fetcher,
     lists_dir: P,
     releases: &[Release],
+    compression: &[Compression],
 ) -> Result<(), Error> {
-    let lists = extract_downloads(releases).with_context(|_| format_err!("filtering releases"))?;
+    let lists = extract_downloads(releases, compression)
+        .with_context(|_| format_err!("filtering releases"))?;
 
     let temp_dir = tempfile::Builder::new()
         .prefix(".fapt-lists")
         .tempdir_in(&lists_dir)
         .with_context(|_| format_err!("creating temporary directory"))?;
 
+    let missing =
+        |list: &&DownloadableListing| !lists_dir.as_ref().join(list.local_name()).exists();
+
+    update_by_diffs(
+        fetcher,
+        lists_dir.as_ref(),
+        temp_dir.as_ref(),
+        &lists.iter().filter(missing).collect::<Vec<_>>(),
+    );
+
     let downloads: Vec<fetch::Download> = lists
         .iter()
-        .filter_map(|list| {
-            let local_name = list.local_name();
-
-            if lists_dir.as_ref().join(&local_name).exists() {
-                None
-            } else {
-                Some(fetch::Download::from_to(
-                    list.url.clone(),
-                    temp_dir.as_ref().join(local_name),
-                ))
-            }
+        .filter(missing)
+        .map(|list| {
+            let mut download = fetch::Download::from_to(
+                list.url.clone(),
+                temp_dir.as_ref().join(list.local_name()),
+            );
+            download.mirrors = list.mirrors.clone();
+            download.hashes = Some(list.compressed_hashes);
+            download
         })
         .collect();
 
-    fetch::fetch(client, &downloads).with_context(|_| format_err!("downloading listed files"))?;
+    fetcher
+        .fetch(&downloads)
+        .with_context(|_| format_err!("downloading listed files"))?;
 
     for list in lists {
         store_list_item(&list, &temp_dir, &lists_dir)?;
@@ -102,6 +182,138 @@ pub fn download_files<P: AsRef<Path>>(
     Ok(())
 }
 
+/// Bring any of the `lists` we can up to date by patching a copy we already have.
+///
+/// This is only an optimisation, so any problem (e.g. our copy is too old, or a patch is missing)
+/// just leaves that _Listing_ missing, to be downloaded in full.
+fn update_by_diffs(
+    fetcher: &fetch::Fetcher,
+    lists_dir: &Path,
+    temp_dir: &Path,
+    lists: &[&DownloadableListing],
+) {
+    let candidates: Vec<(&DownloadableListing, &Diffs, PathBuf)> = lists
+        .iter()
+        .filter_map(|list| {
+            let diffs = list.diffs.as_ref()?;
+            let index = temp_dir.join(format!("{}.diff-Index", list.local_name()));
+            Some((*list, diffs, index))
+        })
+        .collect();
+
+    if candidates.is_empty() {
+        return;
+    }
+
+    let downloads: Vec<fetch::Download> = candidates
+        .iter()
+        .filter_map(|(_, diffs, index)| diffs.download("Index", index, diffs.index_hashes).ok())
+        .collect();
+
+    // failures are left for the full download
+    let _ = fetcher.fetch(&downloads);
+
+    let mut patch_downloads = Vec::new();
+    let mut plans = Vec::new();
+
+    for (list, diffs, index) in candidates {
+        if let Ok(Some((plan, downloads))) = plan_diffs(list, diffs, &index, lists_dir, temp_dir) {
+            plans.push(plan);
+            patch_downloads.extend(downloads);
+        }
+    }
+
+    let _ = fetcher.fetch(&patch_downloads);
+
+    for plan in plans {
+        let _ = apply_diffs(&plan, lists_dir);
+    }
+}
+
+fn plan_diffs<'l>(
+    list: &'l DownloadableListing,
+    diffs: &Diffs,
+    index: &Path,
+    lists_dir: &Path,
+    temp_dir: &Path,
+) -> Result<Option<(Patching<'l>, Vec<fetch::Download>)>, Error> {
+    if !index.exists() {
+        return Ok(None);
+    }
+
+    let index = pdiff::parse_index(&fs::read_to_string(index)?)?;
+    ensure!(
+        Some(index.current) == list.decompressed_hashes.sha256,
+        "index doesn't describe the expected listing"
+    );
+
+    // the most recent copy we have, so the fewest patches
+    let old = match index
+        .history
+        .iter()
+        .rev()
+        .map(|entry| lists_dir.join(hex::encode(entry.sha256)))
+        .find(|path| path.exists())
+    {
+        Some(old) => old,
+        None => return Ok(None),
+    };
+
+    let old_hash = checksum::parse_sha256(
+        old.file_name()
+            .and_then(|name| name.to_str())
+            .expect("we created this name"),
+    )?;
+
+    let names = index
+        .patches_from(&old_hash)
+        .expect("we found this in the history");
+
+    let mut patches = Vec::with_capacity(names.len());
+    let mut downloads = Vec::with_capacity(names.len());
+
+    for name in names {
+        let hashes = |entries: &HashMap<String, IndexEntry>| {
+            entries
+                .get(name)
+                .map(|entry| Hashes {
+                    sha256: Some(entry.sha256),
+                    ..Hashes::default()
+                })
+                .ok_or_else(|| format_err!("patch {:?} missing from index", name))
+        };
+
+        let compressed = hashes(&index.downloads)?;
+        let path = temp_dir.join(format!("{}.gz", name));
+
+        downloads.push(diffs.download(&format!("{}.gz", name), &path, compressed)?);
+        patches.push((path, hashes(&index.patches)?));
+    }
+
+    Ok(Some((Patching { list, old, patches }, downloads)))
+}
+
+fn apply_diffs(plan: &Patching, lists_dir: &Path) -> Result<(), Error> {
+    let mut content = fs::read(&plan.old)?;
+
+    for (path, hashes) in &plan.patches {
+        let mut patch = io::Cursor::new(Vec::new());
+        decompress(Compression::Gz, fs::File::open(path)?, &mut patch, *hashes)?;
+        content = pdiff::apply(&content, patch.get_ref())
+            .with_context(|_| format_err!("applying {:?}", path))?;
+    }
+
+    checksum::validate(content.as_slice(), plan.list.decompressed_hashes)
+        .with_context(|_| format_err!("validating patched listing"))?;
+
+    let mut temp = PersistableTempFile::new_in(lists_dir)?;
+    temp.write_all(&content)?;
+    temp.persist_by_rename(lists_dir.join(plan.list.local_name()))
+        .map_err(|e| e.error)?;
+
+    Ok(())
+}
+
 fn store_list_item<P: AsRef<Path>, Q: AsRef<Path>>(
     list: &DownloadableListing,
     temp_dir: P,
@@ -120,38 +332,63 @@ fn store_list_item<P: AsRef<Path>, Q: AsRef<Path>>(
     checksum::validate(&mut temp, list.compressed_hashes)
         .with_context(|_| format_err!("validating downloaded file: {:?}", temp_path))?;
 
-    match list.codec {
-        Compression::None => fs::rename(temp_path, destination_path)?,
-        Compression::Gz => {
-            temp.seek(SeekFrom::Start(0))?;
-            let mut uncompressed_temp =
-                PersistableTempFile::new_in(&lists_dir).with_context(|_| {
-                    format_err!("making temporary file in {:?}", lists_dir.as_ref())
-                })?;
-
-            decompress_gz(temp, &mut uncompressed_temp, list.decompressed_hashes)
-                .with_context(|_| format_err!("decomressing {:?}", temp_path))?;
-
-            uncompressed_temp
-                .persist_by_rename(destination_path)
-                .map_err(|e| e.error)
-                .with_context(|_| format_err!("storing decompressed file"))?;
-        }
+    if Compression::None == list.codec {
+        fs::rename(temp_path, destination_path)?;
+        return Ok(());
     }
 
+    temp.seek(SeekFrom::Start(0))?;
+    let mut uncompressed_temp = PersistableTempFile::new_in(&lists_dir)
+        .with_context(|_| format_err!("making temporary file in {:?}", lists_dir.as_ref()))?;
+
+    decompress(
+        list.codec,
+        temp,
+        &mut uncompressed_temp,
+        list.decompressed_hashes,
+    )
+    .with_context(|_| format_err!("decompressing {:?}", temp_path))?;
+
+    uncompressed_temp
+        .persist_by_rename(destination_path)
+        .map_err(|e| e.error)
+        .with_context(|_| format_err!("storing decompressed file"))?;
+
     Ok(())
 }
 
-fn decompress_gz<R: Read, F: Read + Write + Seek>(
-    mut compressed: R,
+fn decompress<R: Read, F: Read + Write + Seek>(
+    codec: Compression,
+    compressed: R,
     mut uncompressed: F,
     decompressed_hashes: Hashes,
 ) -> Result<(), Error> {
-    io::copy(
-        &mut GzDecoder::new(io::BufReader::new(&mut compressed)),
-        &mut uncompressed,
-    )
-    .with_context(|_| format_err!("decomressing"))?;
+    let mut compressed = io::BufReader::new(compressed);
+
+    match codec {
+        Compression::None => io::copy(&mut compressed, &mut uncompressed).map(|_| ())?,
+        Compression::Gz => {
+            io::copy(&mut GzDecoder::new(compressed), &mut uncompressed).map(|_| ())?
+        }
+        Compression::Xz => lzma_rs::xz_decompress(&mut compressed, &mut uncompressed)
+            .map_err(|e| format_err!("{}", e))?,
+        Compression::Bz2 => io::copy(
+            &mut bzip2_rs::DecoderReader::new(compressed),
+            &mut uncompressed,
+        )
+        .map(|_| ())?,
+        Compression::Zstd => io::copy(
+            &mut ruzstd::decoding::StreamingDecoder::new(compressed)
+                .map_err(|e| format_err!("{}", e))?,
+            &mut uncompressed,
+        )
+        .map(|_| ())?,
+        Compression::Lz4 => io::copy(
+            &mut lz4_flex::frame::FrameDecoder::new(compressed),
+            &mut uncompressed,
+        )
+        .map(|_| ())?,
+    }
 
     uncompressed
         .seek(SeekFrom::Start(0))
@@ -170,6 +407,16 @@ pub fn selected_listings(release: &Release) -> Vec<Listing> {
         let directory = if entry.src { "source" } else { "binary" };
         let name = if entry.src { "Sources" } else { "Packages" };
 
+        if entry.is_flat() {
+            ret.push(Listing {
+                component: String::new(),
+                arch: None,
+                directory: String::new(),
+                name: name.to_string(),
+            });
+            continue;
+        }
+
         for component in &entry.components {
             if entry.src {
                 ret.push(Listing {
@@ -179,20 +426,14 @@ pub fn selected_listings(release: &Release) -> Vec<Listing> {
                     name: name.to_string(),
                 })
             } else {
-                for arch in &release.req.arches {
-                    if !release.file.arches.contains(arch) {
+                for arch in entry.options.arches.apply(&release.req.arches) {
+                    if !release.file.arches.contains(&arch) {
                         continue;
                     }
 
-                    if let Some(ref entry_arch) = entry.arch {
-                        if arch != entry_arch {
-                            continue;
-                        }
-                    }
-
                     ret.push(Listing {
                         component: component.to_string(),
-                        arch: Some(arch.to_string()),
+                        arch: Some(arch),
                         directory: directory.to_string(),
                         name: name.to_string(),
                     })
@@ -204,13 +445,16 @@ pub fn selected_listings(release: &Release) -> Vec<Listing> {
     ret
 }
 
-pub fn extract_downloads(releases: &[Release]) -> Result<Vec<DownloadableListing>, Error> {
+pub fn extract_downloads(
+    releases: &[Release],
+    compression: &[Compression],
+) -> Result<Vec<DownloadableListing>, Error> {
     releases
         .iter()
         .flat_map(|rel| {
             selected_listings(rel)
                 .into_iter()
-                .map(move |listing| find_file_easy(rel, &listing))
+                .map(move |listing| find_file_easy(rel, &listing, compression))
         })
         .collect()
 }
@@ -220,9 +464,10 @@ pub fn sections_in<P: AsRef<Path>>(
     listing: &Listing,
     lists_dir: P,
 ) -> Result<rfc822::Blocks<fs::File>, Error> {
+    // the local name is derived from the decompressed file, so the compression doesn't matter
     let local_path = lists_dir
         .as_ref()
-        .join(find_file_easy(release, listing)?.local_name());
+        .join(find_file_easy(release, listing, &Compression::DEFAULT_ORDER)?.local_name());
     Ok(rfc822::Blocks::new(
         fs::File::open(&local_path)
             .with_context(|_| format_err!("Couldn't open {:?}", local_path))?,
@@ -230,14 +475,36 @@ pub fn sections_in<P: AsRef<Path>>(
     ))
 }
 
-pub fn find_file_easy(release: &Release, listing: &Listing) -> Result<DownloadableListing, Error> {
-    Ok(find_file(
+pub fn find_file_easy(
+    release: &Release,
+    listing: &Listing,
+    compression: &[Compression],
+) -> Result<DownloadableListing, Error> {
+    let mut found = find_file(
         &release.req.dists()?,
         &release.file.contents,
         release.file.acquire_by_hash,
         &listing,
+        compression,
     )
-    .with_context(|_| format_err!("finding {:?} in {:?}", listing, release))?)
+    .with_context(|_| format_err!("finding {:?} in {:?}", listing, release))?;
+
+    found.mirrors = release.req.alternatives(&found.url)?;
+
+    let pdiffs = !release
+        .sources_entries
+        .iter()
+        .any(|entry| Some(false) == entry.options.pdiffs);
+
+    if !pdiffs {
+        found.diffs = None;
+    }
+
+    if let Some(diffs) = found.diffs.as_mut() {
+        diffs.mirrors = release.req.alternatives(&diffs.dir)?;
+    }
+
+    Ok(found)
 }
 
 pub fn find_file(
@@ -245,48 +512,334 @@ pub fn find_file(
     contents: &[ReleaseContent],
     acquire_by_hash: bool,
     listing: &Listing,
+    compression: &[Compression],
 ) -> Result<DownloadableListing, Error> {
-    let directory = listing
-        .arch
-        .as_ref()
-        .map(|arch| format!("{}-{}", listing.directory, arch))
-        .unwrap_or_else(|| listing.directory.to_string());
-
-    let base = format!("{}/{}/{}", listing.component, directory, listing.name);
+    let parent = if listing.component.is_empty() {
+        String::new()
+    } else {
+        let directory = listing
+            .arch
+            .as_ref()
+            .map(|arch| format!("{}-{}", listing.directory, arch))
+            .unwrap_or_else(|| listing.directory.to_string());
 
-    let gz_name = format!("{}{}", base, Compression::Gz.suffix());
+        format!("{}/{}/", listing.component, directory)
+    };
 
-    let mut gz_hashes = None;
-    let mut raw_hashes = None;
+    let base = format!("{}{}", parent, listing.name);
 
-    for content in contents {
-        if content.name == base {
-            raw_hashes = Some(content.hashes);
-        } else if content.name == gz_name {
-            gz_hashes = Some(content.hashes);
-        }
-    }
+    let hashes_of = |name: &str| {
+        contents
+            .iter()
+            .find(|content| content.name == name)
+            .map(|content| content.hashes)
+    };
 
     let raw_hashes =
-        raw_hashes.ok_or_else(|| format_err!("file {:?} not found in release", base))?;
-
-    let url = base_url.join(&if acquire_by_hash {
-        format!(
-            "{}/{}/by-hash/SHA256/{}",
-            listing.component,
-            directory,
-            hex::encode(gz_hashes.unwrap_or(raw_hashes).sha256)
-        )
-    } else {
-        gz_hashes.map(|_| gz_name).unwrap_or(base)
+        hashes_of(&base).ok_or_else(|| format_err!("file {:?} not found in release", base))?;
+
+    // our copy is named after it, regardless of the `HashPolicy`
+    ensure!(
+        raw_hashes.sha256.is_some(),
+        "sha256 missing for {:?} in release file; refusing to process",
+        base
+    );
+
+    let (codec, name, compressed_hashes) = compression
+        .iter()
+        .filter_map(|codec| {
+            let name = format!("{}{}", base, codec.suffix());
+            hashes_of(&name).map(|hashes| (*codec, name, hashes))
+        })
+        .next()
+        .ok_or_else(|| {
+            format_err!(
+                "file {:?} not available in any of the accepted compressions: {:?}",
+                base,
+                compression
+            )
+        })?;
+
+    let diffs = match hashes_of(&format!("{}.diff/Index", base)) {
+        Some(index_hashes) => Some(Diffs {
+            dir: base_url.join(&format!("{}.diff/", base))?,
+            mirrors: Vec::new(),
+            index_hashes,
+        }),
+        None => None,
+    };
+
+    let url = base_url.join(&match compressed_hashes.sha256 {
+        Some(sha256) if acquire_by_hash => {
+            format!("{}by-hash/SHA256/{}", parent, hex::encode(sha256))
+        }
+        _ => name,
     })?;
 
     Ok(DownloadableListing {
         url,
-        codec: gz_hashes
-            .map(|_| Compression::Gz)
-            .unwrap_or(Compression::None),
-        compressed_hashes: gz_hashes.unwrap_or(raw_hashes),
+        mirrors: Vec::new(),
+        codec,
+        compressed_hashes,
         decompressed_hashes: raw_hashes,
+        diffs,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::io::Write;
+
+    use reqwest::Url;
+    use sha2::Digest;
+    use sha2::Sha256;
+
+    use super::decompress;
+    use super::find_file;
+    use super::update_by_diffs;
+    use super::Compression;
+    use super::Diffs;
+    use super::DownloadableListing;
+    use super::Listing;
+    use crate::checksum::Hashes;
+    use crate::fetch::Fetcher;
+    use crate::release::ReleaseContent;
+
+    const ORDER: [Compression; 6] = Compression::DEFAULT_ORDER;
+
+    fn content(name: &str, fill: u8) -> ReleaseContent {
+        ReleaseContent {
+            len: 0,
+            name: name.to_string(),
+            hashes: Hashes {
+                md5: Some([fill; 16]),
+                sha256: Some([fill; 32]),
+                ..Hashes::default()
+            },
+        }
+    }
+
+    #[test]
+    fn flat() {
+        let base = Url::parse("http://foo/repo/").unwrap();
+        let contents = [content("Packages", 1), content("Packages.gz", 2)];
+        let listing = Listing {
+            component: String::new(),
+            arch: None,
+            directory: String::new(),
+            name: "Packages".to_string(),
+        };
+
+        let found = find_file(&base, &contents, false, &listing, &ORDER).unwrap();
+        assert_eq!("http://foo/repo/Packages.gz", found.url.as_str());
+        assert_eq!(Some([1; 32]), found.decompressed_hashes.sha256);
+
+        let found = find_file(&base, &contents, true, &listing, &ORDER).unwrap();
+        assert_eq!(
+            format!("http://foo/repo/by-hash/SHA256/{}", hex::encode([2; 32])),
+            found.url.as_str()
+        );
+    }
+
+    #[test]
+    fn weak_hashes() {
+        let base = Url::parse("http://foo/repo/").unwrap();
+        let listing = Listing {
+            component: String::new(),
+            arch: None,
+            directory: String::new(),
+            name: "Packages".to_string(),
+        };
+
+        let mut compressed = content("Packages.gz", 2);
+        compressed.hashes.sha256 = None;
+        let contents = [content("Packages", 1), compressed.clone()];
+
+        // the download is checked against the `HashPolicy` later, but can't be fetched by-hash
+        let found = find_file(&base, &contents, true, &listing, &ORDER).unwrap();
+        assert_eq!("http://foo/repo/Packages.gz", found.url.as_str());
+
+        let mut raw = content("Packages", 1);
+        raw.hashes.sha256 = None;
+        assert!(find_file(&base, &[raw, compressed], false, &listing, &ORDER).is_err());
+    }
+
+    #[test]
+    fn component() {
+        let base = Url::parse("http://foo/debian/dists/sid/").unwrap();
+        let contents = [content("main/binary-amd64/Packages", 1)];
+        let listing = Listing {
+            component: "main".to_string(),
+            arch: Some("amd64".to_string()),
+            directory: "binary".to_string(),
+            name: "Packages".to_string(),
+        };
+
+        let found = find_file(&base, &contents, false, &listing, &ORDER).unwrap();
+        assert_eq!(
+            "http://foo/debian/dists/sid/main/binary-amd64/Packages",
+            found.url.as_str()
+        );
+    }
+
+    #[test]
+    fn preference() {
+        let base = Url::parse("http://foo/repo/").unwrap();
+        let contents = [
+            content("Packages", 1),
+            content("Packages.gz", 2),
+            content("Packages.xz", 3),
+            content("Packages.lz4", 4),
+        ];
+        let listing = Listing {
+            component: String::new(),
+            arch: None,
+            directory: String::new(),
+            name: "Packages".to_string(),
+        };
+
+        let found = find_file(&base, &contents, false, &listing, &ORDER).unwrap();
+        assert_eq!("http://foo/repo/Packages.xz", found.url.as_str());
+        assert_eq!(Compression::Xz, found.codec);
+        assert_eq!(Some([3; 32]), found.compressed_hashes.sha256);
+
+        let lz4_first = [Compression::Zstd, Compression::Lz4, Compression::Gz];
+        let found = find_file(&base, &contents, false, &listing, &lz4_first).unwrap();
+        assert_eq!(Compression::Lz4, found.codec);
+
+        assert!(find_file(&base, &contents, false, &listing, &[Compression::Bz2]).is_err());
+    }
+
+    #[test]
+    fn decompression() {
+        let original = b"Package: foo\nVersion: 1\n".repeat(100);
+        let hashes = Hashes {
+            sha256: Some(Sha256::digest(&original).into()),
+            ..Hashes::default()
+        };
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&original).unwrap();
+
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut io::Cursor::new(&original), &mut xz).unwrap();
+
+        let mut lz4 = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        lz4.write_all(&original).unwrap();
+
+        // our bzip2 and zstd decoders can't compress, so these are from `bzip2 -9` and `zstd -19`
+        let bz2 = hex::decode(
+            "425a6839314159265359e3d83aff00018fdb8000104000201041002ba998002000911434d300014aa2\
+             6118689991608b4116845822e08b6116e45822e08bb22e48be917c22f48bb22e845e117845d117a458\
+             2ee48a70a121c7b075fe",
+        )
+        .unwrap();
+        let zstd = hex::decode(
+            "28b52ffd646008050100c05061636b6167653a20666f6f0a56657273696f6e3a20310a01001465fd39\
+             06ea9f9528",
+        )
+        .unwrap();
+
+        for (codec, compressed) in vec![
+            (Compression::None, original.clone()),
+            (Compression::Gz, gz.finish().unwrap()),
+            (Compression::Xz, xz),
+            (Compression::Bz2, bz2),
+            (Compression::Zstd, zstd),
+            (Compression::Lz4, lz4.finish().unwrap()),
+        ] {
+            let mut out = io::Cursor::new(Vec::new());
+            decompress(codec, io::Cursor::new(&compressed), &mut out, hashes).unwrap();
+            assert_eq!(original, out.into_inner(), "{:?}", codec);
+
+            let mut out = io::Cursor::new(Vec::new());
+            let mut corrupt = compressed.clone();
+            corrupt.truncate(compressed.len() / 2);
+            assert!(
+                decompress(codec, io::Cursor::new(&corrupt), &mut out, hashes).is_err(),
+                "{:?}",
+                codec
+            );
+        }
+    }
+
+    fn sha256(data: &[u8]) -> Hashes {
+        Hashes {
+            sha256: Some(Sha256::digest(data).into()),
+            ..Hashes::default()
+        }
+    }
+
+    fn gz(data: &[u8]) -> Vec<u8> {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(data).unwrap();
+        gz.finish().unwrap()
+    }
+
+    #[test]
+    fn diffs() {
+        let dir = tempfile::tempdir().unwrap();
+        let lists_dir = dir.path().join("lists");
+        let mirror = dir.path().join("Packages.diff");
+        fs::create_dir_all(&lists_dir).unwrap();
+        fs::create_dir_all(&mirror).unwrap();
+
+        let v1 = b"Package: a\n\nPackage: b\n";
+        let v2 = b"Package: a\n\nPackage: c\n";
+        let v3 = b"Package: z\n\nPackage: a\n\nPackage: c\n";
+        let p1 = b"3c\nPackage: c\n.\n";
+        let p2 = b"0a\nPackage: z\n\n.\n";
+
+        fs::write(lists_dir.join(hex::encode(sha256(v1).sha256.unwrap())), v1).unwrap();
+        fs::write(mirror.join("p1.gz"), gz(p1)).unwrap();
+        fs::write(mirror.join("p2.gz"), gz(p2)).unwrap();
+
+        let line = |data: &[u8], name: &str| {
+            format!(
+                " {} {} {}\n",
+                hex::encode(sha256(data).sha256.unwrap()),
+                data.len(),
+                name
+            )
+        };
+
+        let index = format!(
+            "SHA256-Current: {} {}\nSHA256-History:\n{}{}SHA256-Patches:\n{}{}SHA256-Download:\n{}{}",
+            hex::encode(sha256(v3).sha256.unwrap()),
+            v3.len(),
+            line(v1, "p1"),
+            line(v2, "p2"),
+            line(p1, "p1"),
+            line(p2, "p2"),
+            line(&gz(p1), "p1.gz"),
+            line(&gz(p2), "p2.gz"),
+        );
+        fs::write(mirror.join("Index"), &index).unwrap();
+
+        let list = DownloadableListing {
+            url: Url::from_file_path(dir.path().join("Packages")).unwrap(),
+            mirrors: Vec::new(),
+            codec: Compression::None,
+            compressed_hashes: sha256(v3),
+            decompressed_hashes: sha256(v3),
+            diffs: Some(Diffs {
+                dir: Url::from_directory_path(&mirror).unwrap(),
+                mirrors: Vec::new(),
+                index_hashes: sha256(index.as_bytes()),
+            }),
+        };
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let fetcher = Fetcher::new(Box::new(reqwest::Client::new()));
+        update_by_diffs(&fetcher, &lists_dir, temp_dir.path(), &[&list]);
+
+        assert_eq!(
+            &v3[..],
+            fs::read(lists_dir.join(list.local_name()))
+                .unwrap()
+                .as_slice()
+        );
+    }
+}
@@ -20,6 +20,16 @@ impl Arch {
         self.kernel.is_none() && self.cpu.is_none()
     }
 
+    /// Whether this architecture, which may be a wildcard like `linux-any`, includes
+    /// the concrete architecture `arch`, e.g. `amd64`.
+    ///
+    /// `amd64` and `any-amd64` are represented identically, so both match any kernel.
+    pub fn matches(&self, arch: &Arch) -> bool {
+        let kernel = arch.kernel.or(Some(Kernel::Linux));
+        (self.kernel.is_none() || self.kernel == kernel)
+            && (self.cpu.is_none() || self.cpu == arch.cpu)
+    }
+
     pub fn boogered() -> Arch {
         Arch {
             kernel: None,
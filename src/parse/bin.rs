@@ -6,6 +6,11 @@ use self::rfc822::RfcMapExt;
 use super::deps::parse_dep;
 use super::deps::Dependency;
 use super::pkg;
+use crate::checksum::parse_md5;
+use crate::checksum::parse_sha1;
+use crate::checksum::parse_sha256;
+use crate::checksum::parse_sha512;
+use crate::checksum::Hashes;
 use crate::rfc822;
 
 /// Binary package specific fields.
@@ -41,10 +46,28 @@ pub(super) fn parse_bin(it: &mut rfc822::Map) -> Result<Binary, Error> {
         Some(super::pkg::File {
             name: it.remove_value("Filename").one_line_req()?.to_string(),
             size: it.remove_value("Size").one_line_req()?.parse()?,
-            md5: it.remove_value("MD5sum").one_line_req()?.to_string(),
-            sha1: it.remove_value("SHA1").one_line_req()?.to_string(),
-            sha256: it.remove_value("SHA256").one_line_req()?.to_string(),
-            sha512: String::new(),
+            hashes: Hashes {
+                md5: it
+                    .remove_value("MD5sum")
+                    .one_line()?
+                    .map(parse_md5)
+                    .inside_out()?,
+                sha1: it
+                    .remove_value("SHA1")
+                    .one_line()?
+                    .map(parse_sha1)
+                    .inside_out()?,
+                sha256: it
+                    .remove_value("SHA256")
+                    .one_line()?
+                    .map(parse_sha256)
+                    .inside_out()?,
+                sha512: it
+                    .remove_value("SHA512")
+                    .one_line()?
+                    .map(parse_sha512)
+                    .inside_out()?,
+            },
         })
     } else {
         None
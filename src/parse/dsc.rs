@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use failure::bail;
+use failure::err_msg;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+use gpgrv::Keyring;
+
+use super::arch;
+use super::ident;
+use super::src;
+use crate::rfc822;
+use crate::rfc822::RfcMapExt;
+use crate::signing::GpgClient;
+
+const SIGNED_HEADER: &str = "-----BEGIN PGP SIGNED MESSAGE-----";
+const SIGNATURE_HEADER: &str = "-----BEGIN PGP SIGNATURE-----";
+
+/// A standalone source package description, e.g. `hello_2.10-2.dsc`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dsc {
+    /// The `Source` field.
+    pub name: String,
+    pub version: String,
+    pub arches: arch::Arches,
+
+    pub maintainer: Vec<ident::Identity>,
+    pub homepage: Option<String>,
+
+    /// The fields shared with _Sources_. A `.dsc` doesn't know its `Directory`,
+    /// so `directory` is empty.
+    pub source: src::Source,
+
+    pub unparsed: HashMap<String, Vec<String>>,
+}
+
+impl Dsc {
+    /// Parse a `.dsc`, which may be clearsigned. The signature is *not* checked.
+    pub fn parse(dsc: &str) -> Result<Dsc, Error> {
+        if !dsc.starts_with(SIGNED_HEADER) {
+            return parse_fields(dsc);
+        }
+
+        parse_fields(&strip_signature(dsc)?)
+    }
+
+    /// Parse a clearsigned `.dsc`, which must have a valid signature from a key in `keyring`.
+    pub fn parse_verified<R: BufRead>(from: R, keyring: &Keyring) -> Result<Dsc, Error> {
+        let mut content = Vec::new();
+        GpgClient::new(keyring)
+            .verify_clearsigned_into(from, &mut content)
+            .with_context(|_| format_err!("verifying signature"))?;
+        parse_fields(&String::from_utf8(content)?)
+    }
+}
+
+/// The signed text of a clearsigned document: after the armour headers, before the signature,
+/// with dash-escaping undone.
+fn strip_signature(signed: &str) -> Result<String, Error> {
+    let mut lines = signed.lines().skip(1);
+
+    // armour headers, e.g. `Hash: SHA256`, end at the first blank line
+    loop {
+        match lines.next() {
+            Some(line) if line.trim().is_empty() => break,
+            Some(_) => continue,
+            None => bail!("no body in signed document"),
+        }
+    }
+
+    let mut body = String::with_capacity(signed.len());
+    for line in lines {
+        if line.starts_with(SIGNATURE_HEADER) {
+            return Ok(body);
+        }
+        body.push_str(line.strip_prefix("- ").unwrap_or(line));
+        body.push('\n');
+    }
+
+    bail!("no signature after signed document")
+}
+
+fn parse_fields(dsc: &str) -> Result<Dsc, Error> {
+    let mut map = rfc822::fields_in_block(dsc).collect_to_map()?;
+
+    let name = map
+        .remove_value("Source")
+        .one_line_req()
+        .with_context(|_| err_msg("no Source"))?
+        .to_string();
+
+    let source = src::parse_src_in(&mut map, String::new())
+        .with_context(|_| format_err!("source fields in {:?}", name))?;
+
+    Ok(Dsc {
+        version: map.remove_value("Version").one_line_req()?.to_string(),
+        arches: map
+            .remove_value("Architecture")
+            .one_line_req()?
+            .split_whitespace()
+            .map(|s| s.parse())
+            .collect::<Result<_, Error>>()
+            .with_context(|_| err_msg("reading Architecture"))?,
+        maintainer: ident::read(map.remove_value("Maintainer").one_line_req()?)?,
+        homepage: map.remove_value("Homepage").one_line_owned()?,
+        source,
+        unparsed: map
+            .into_iter()
+            .map(|(k, v)| {
+                (
+                    k.to_string(),
+                    v.into_iter().map(|v| v.to_string()).collect(),
+                )
+            })
+            .collect(),
+        name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use gpgrv::Keyring;
+
+    use super::Dsc;
+
+    /// Clearsigned by a throwaway key, whose public half is `SIGNER`.
+    const SIGNED: &str = include_str!("../../tests/dsc/hello_2.10-2.dsc");
+    const SIGNER: &str = include_str!("../../tests/dsc/signer.asc");
+
+    fn signer() -> Keyring {
+        let mut keyring = Keyring::new();
+        keyring
+            .append_keys_from_armoured(io::Cursor::new(SIGNER))
+            .unwrap();
+        keyring
+    }
+
+    const DSC: &str = "-----BEGIN PGP SIGNED MESSAGE-----
+Hash: SHA256
+
+Format: 3.0 (quilt)
+Source: hello
+Binary: hello
+Architecture: any
+Version: 2.10-2
+Maintainer: Santiago Vila <sanvila@debian.org>
+Homepage: http://www.gnu.org/software/hello/
+Standards-Version: 4.3.0
+Testsuite: autopkgtest
+Testsuite-Triggers: coreutils, make
+Dgit: 2d0dd3cbb8b1e1e1bb3b8c4b22d4c4de8e34d0ac debian archive/debian/2.10-2 https://git.dgit.debian.org/hello
+Build-Depends: debhelper (>= 9.20120311)
+Package-List:
+ hello deb devel optional arch=any
+Checksums-Sha1:
+ f7bebf6f9c62a2295e889f66e05ce9bfaed9ace3 725946 hello_2.10.orig.tar.gz
+ a3fd8ae4a12e3b5ceb81bdba17a8d7ab2cdfd7cd 6132 hello_2.10-2.debian.tar.xz
+Checksums-Sha256:
+ 31e066137a962676e89f69d1b65382de95a7ef7d914b8cb956f41ea72e0f516b 725946 hello_2.10.orig.tar.gz
+ 811ad0255495279fc98dc75f4460da1722f5c1030740cb52638cb80d0fdb24f0 6132 hello_2.10-2.debian.tar.xz
+Files:
+ 6cd0ffea3884a4e79330338dcc2987d6 725946 hello_2.10.orig.tar.gz
+ e4b1cc6fb5dae4ff4b4b8c5e3bf2e4bc 6132 hello_2.10-2.debian.tar.xz
+Original-Maintainer: Someone <someone@example.com>
+-----BEGIN PGP SIGNATURE-----
+
+iQIzBAEBCAAdFiEEAAAAAAAAAAAAAAAAAAAAAAAAAAAFAlwAAAAACgkQAAAAAAAA
+AAAAAA==
+=AAAA
+-----END PGP SIGNATURE-----
+";
+
+    #[test]
+    fn parse() {
+        let dsc = Dsc::parse(DSC).unwrap();
+        assert_eq!("hello", dsc.name);
+        assert_eq!("2.10-2", dsc.version);
+        assert_eq!(vec!["autopkgtest"], dsc.source.testsuite);
+        assert_eq!(vec!["coreutils", "make"], dsc.source.testsuite_triggers);
+        assert!(dsc
+            .source
+            .dgit
+            .as_ref()
+            .unwrap()
+            .starts_with("2d0dd3cbb8b1e1e1bb3b8c4b22d4c4de8e34d0ac "));
+        assert_eq!(2, dsc.source.files.len());
+        for file in &dsc.source.files {
+            assert!(file.hashes.md5.is_some());
+            assert!(file.hashes.sha1.is_some());
+            assert!(file.hashes.sha256.is_some());
+        }
+        assert!(dsc.unparsed.contains_key("Original-Maintainer"));
+
+        let unsigned =
+            &DSC[DSC.find("Format:").unwrap()..DSC.find("-----BEGIN PGP SIGNATURE").unwrap()];
+        let unsigned = Dsc::parse(unsigned).unwrap();
+        assert_eq!(dsc.version, unsigned.version);
+        assert_eq!(dsc.source.dgit, unsigned.source.dgit);
+        assert_eq!(dsc.unparsed, unsigned.unparsed);
+    }
+
+    #[test]
+    fn unverifiable() {
+        assert!(Dsc::parse_verified(DSC.as_bytes(), &Keyring::new()).is_err());
+    }
+
+    #[test]
+    fn verified() {
+        let dsc = Dsc::parse_verified(SIGNED.as_bytes(), &signer()).unwrap();
+        assert_eq!("hello", dsc.name);
+        assert_eq!(Dsc::parse(SIGNED).unwrap(), dsc);
+
+        // a real signature, but not from an archive key
+        let mut distro = Keyring::new();
+        distro
+            .append_keys_from(io::Cursor::new(distro_keyring::supported_keys()))
+            .unwrap();
+        assert!(Dsc::parse_verified(SIGNED.as_bytes(), &distro).is_err());
+    }
+
+    #[test]
+    fn tampered() {
+        let tampered = SIGNED.replace("Version: 2.10-2", "Version: 2.10-3");
+        assert_ne!(SIGNED, tampered);
+        assert!(Dsc::parse_verified(tampered.as_bytes(), &signer()).is_err());
+    }
+}
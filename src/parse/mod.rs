@@ -3,6 +3,7 @@
 mod arch;
 mod bin;
 mod deps;
+mod dsc;
 mod ident;
 mod pkg;
 mod src;
@@ -16,7 +17,9 @@ pub use self::deps::Constraint;
 pub use self::deps::ConstraintOperator;
 pub use self::deps::Dependency;
 pub use self::deps::SingleDependency;
+pub use self::dsc::Dsc;
 pub use self::ident::Identity;
+pub use self::pkg::File;
 pub use self::pkg::Package;
 pub use self::pkg::PackageType;
 pub use self::pkg::Priority;
@@ -27,3 +30,20 @@ pub use self::src::SourceFormat;
 pub use self::vcs::Vcs;
 pub use self::vcs::VcsTag;
 pub use self::vcs::VcsType;
+
+/// The _Block_ for a binary _Package_, with the required fields filled in, for tests.
+#[cfg(test)]
+pub(crate) fn test_block(name: &str, version: &str, fields: &str) -> String {
+    format!(
+        "Package: {}\nVersion: {}\nArchitecture: amd64\nSection: misc\n\
+         Maintainer: Foo <foo@example.com>\nDescription: test\n{}",
+        name, version, fields
+    )
+}
+
+/// A binary _Package_, with the required fields filled in, for tests.
+#[cfg(test)]
+pub(crate) fn test_package(name: &str, version: &str, fields: &str) -> Package {
+    let block = test_block(name, version, fields);
+    Package::parse(&mut crate::rfc822::fields_in_block(&block).collect_to_map().unwrap()).unwrap()
+}
@@ -12,6 +12,7 @@ use super::arch;
 use super::bin;
 use super::ident;
 use super::src;
+use crate::checksum::Hashes;
 use crate::rfc822;
 use crate::rfc822::RfcMapExt;
 
@@ -45,10 +46,7 @@ pub struct Package {
 pub struct File {
     pub name: String,
     pub size: u64,
-    pub md5: String,
-    pub sha1: String,
-    pub sha256: String,
-    pub sha512: String,
+    pub hashes: Hashes,
 }
 
 /// https://www.debian.org/doc/debian-policy/#priorities
@@ -1,8 +1,5 @@
-use std::collections::HashMap;
-
 use failure::bail;
 use failure::ensure;
-use failure::err_msg;
 use failure::Error;
 use insideout::InsideOut;
 
@@ -11,6 +8,9 @@ use super::deps::Dependency;
 use super::ident::Identity;
 use super::pkg;
 use super::vcs;
+use crate::checksum::Hashes;
+use crate::release::take_hashes;
+use crate::release::ChecksumFields;
 use crate::rfc822;
 use crate::rfc822::RfcMapExt;
 use std::collections::HashSet;
@@ -35,6 +35,11 @@ pub struct Source {
     pub build_conflict_indep: Vec<Dependency>,
 
     pub uploaders: Vec<Identity>,
+
+    pub testsuite: Vec<String>,
+    pub testsuite_triggers: Vec<String>,
+    /// The commit id, and where to find it, if the upload was made with `dgit`.
+    pub dgit: Option<String>,
 }
 
 /// The `Files` making up a source package
@@ -43,8 +48,7 @@ pub struct Source {
 pub struct SourceArchive {
     pub name: String,
     pub size: u64,
-    pub md5: crate::checksum::MD5,
-    pub sha256: Option<crate::checksum::SHA256>,
+    pub hashes: Hashes,
 }
 
 /// Information on the binary packages built from a source package.
@@ -68,11 +72,17 @@ pub enum SourceFormat {
 }
 
 pub(super) fn parse_src(map: &mut rfc822::Map) -> Result<Source, Error> {
+    let directory = map.remove_value("Directory").one_line_req()?.to_string();
+    parse_src_in(map, directory)
+}
+
+/// The source fields, for a package which isn't in a _Listing_, and hence has no `Directory`.
+pub(super) fn parse_src_in(map: &mut rfc822::Map, directory: String) -> Result<Source, Error> {
     Ok(Source {
         format: parse_format(map.remove_value("Format").one_line_req()?)?,
         binaries: take_package_list(map)?,
         files: take_files(map)?,
-        directory: map.remove_value("Directory").one_line_req()?.to_string(),
+        directory,
         vcs: super::vcs::extract(map)?,
         // TODO: Option<> instead of empty string?
         standards_version: map
@@ -96,9 +106,25 @@ pub(super) fn parse_src(map: &mut rfc822::Map) -> Result<Source, Error> {
             .map(|line| super::ident::read(line))
             .inside_out()?
             .unwrap_or_else(Vec::new),
+        testsuite: take_comma_list(map, "Testsuite")?,
+        testsuite_triggers: take_comma_list(map, "Testsuite-Triggers")?,
+        dgit: map.remove_value("Dgit").one_line_owned()?,
     })
 }
 
+fn take_comma_list(map: &mut rfc822::Map, key: &str) -> Result<Vec<String>, Error> {
+    if !map.contains_key(key) {
+        return Ok(Vec::new());
+    }
+
+    Ok(map
+        .remove_value(key)
+        .split_comma()?
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect())
+}
+
 pub(super) fn parse_format(string: &str) -> Result<SourceFormat, Error> {
     Ok(match string {
         "3.0 (quilt)" => SourceFormat::Quilt3dot0,
@@ -167,30 +193,35 @@ pub(super) fn take_package_list(map: &mut rfc822::Map) -> Result<Vec<SourceBinar
 }
 
 pub(super) fn take_files(map: &mut rfc822::Map) -> Result<Vec<SourceArchive>, Error> {
-    use crate::checksum::parse_md5;
-    use crate::checksum::parse_sha256;
-    use crate::release::take_checksums;
-    let file_and_size_to_md5 =
-        take_checksums(map, "Files")?.ok_or_else(|| err_msg("Files required"))?;
-    let mut file_and_size_to_sha256 =
-        take_checksums(map, "Checksums-Sha256")?.unwrap_or_else(HashMap::new);
-
-    let mut archives = Vec::with_capacity(file_and_size_to_md5.len());
-    for ((name, size), md5) in file_and_size_to_md5 {
-        let sha256 = file_and_size_to_sha256.remove(&(name, size));
+    ensure!(map.contains_key("Files"), "Files required");
+
+    let all = take_hashes(
+        map,
+        &ChecksumFields {
+            md5: "Files",
+            sha1: "Checksums-Sha1",
+            sha256: "Checksums-Sha256",
+            sha512: "Checksums-Sha512",
+        },
+    )?;
+
+    let mut archives = Vec::with_capacity(all.len());
+    for ((name, size), hashes) in all {
+        ensure!(
+            hashes.md5.is_some(),
+            "checksums for a file which wasn't in Files: {:?}",
+            name
+        );
+
         archives.push(SourceArchive {
             name: name.to_string(),
             size,
-            md5: parse_md5(md5)?,
-            sha256: sha256.map(|v| parse_sha256(v)).inside_out()?,
+            hashes,
         })
     }
 
-    ensure!(
-        file_and_size_to_sha256.is_empty(),
-        "sha256sum for a file which didn't exist: {:?}",
-        file_and_size_to_sha256
-    );
+    // the hashes are gathered in a map; give the same order for the same fields
+    archives.sort_by(|left, right| left.name.cmp(&right.name));
 
     Ok(archives)
 }
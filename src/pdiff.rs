@@ -0,0 +1,260 @@
+//! Incremental updates of _Listings_, from `Packages.diff/Index` and its `ed`-style patches.
+
+use std::collections::HashMap;
+
+use failure::bail;
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+
+use crate::checksum;
+use crate::checksum::SHA256;
+use crate::rfc822;
+use crate::rfc822::RfcMapExt;
+
+/// A file mentioned in an `Index`: its (uncompressed) hash and length, and its name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub sha256: SHA256,
+    pub len: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Index {
+    /// The hash of the _Listing_ after applying all the patches.
+    pub current: SHA256,
+    /// The _Listings_ the patches apply to, oldest first, named after their patch.
+    pub history: Vec<IndexEntry>,
+    /// The uncompressed patches.
+    pub patches: HashMap<String, IndexEntry>,
+    /// The compressed patches, as they are downloaded, keyed by their uncompressed name.
+    pub downloads: HashMap<String, IndexEntry>,
+    /// Each patch goes directly to the current _Listing_, instead of to the next in the history.
+    pub merged: bool,
+}
+
+impl Index {
+    /// The patches to apply, in order, to turn the _Listing_ with the hash `from`
+    /// into the current one.
+    pub fn patches_from(&self, from: &SHA256) -> Option<Vec<&str>> {
+        let start = self
+            .history
+            .iter()
+            .position(|entry| &entry.sha256 == from)?;
+
+        let names = self.history[start..]
+            .iter()
+            .map(|entry| entry.name.as_str());
+
+        Some(if self.merged {
+            names.take(1).collect()
+        } else {
+            names.collect()
+        })
+    }
+}
+
+pub fn parse_index(index: &str) -> Result<Index, Error> {
+    let mut data = rfc822::fields_in_block(index).collect_to_map()?;
+
+    let current = data.remove_value("SHA256-Current").one_line_req()?;
+    let current = checksum::parse_sha256(
+        current
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| format_err!("empty SHA256-Current"))?,
+    )?;
+
+    let history = entries(data.remove("SHA256-History").unwrap_or_default())?;
+
+    let patches = entries(data.remove("SHA256-Patches").unwrap_or_default())?
+        .into_iter()
+        .map(|entry| (entry.name.to_string(), entry))
+        .collect();
+
+    let downloads = entries(data.remove("SHA256-Download").unwrap_or_default())?
+        .into_iter()
+        .map(|entry| (entry.name.trim_end_matches(".gz").to_string(), entry))
+        .collect();
+
+    let merged = data
+        .remove_value("X-Patch-Precedence")
+        .one_line()?
+        .map(|s| "merged" == s)
+        .unwrap_or(false);
+
+    Ok(Index {
+        current,
+        history,
+        patches,
+        downloads,
+        merged,
+    })
+}
+
+fn entries(lines: Vec<&str>) -> Result<Vec<IndexEntry>, Error> {
+    lines
+        .into_iter()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            ensure!(3 == parts.len(), "invalid index line: {:?}", line);
+            Ok(IndexEntry {
+                sha256: checksum::parse_sha256(parts[0])?,
+                len: parts[1].parse()?,
+                name: parts[2].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Apply an `ed` script, as produced by `diff --ed`, to some lines.
+///
+/// The script must have its commands in descending line order, as `diff` produces,
+/// so the output can be built in one pass, copying the untouched lines between commands.
+pub fn apply(original: &[u8], patch: &[u8]) -> Result<Vec<u8>, Error> {
+    let lines = split_lines(original);
+    let mut script = split_lines(patch).into_iter().enumerate();
+
+    // (first line replaced, line after the last replaced, text to insert), all 0-based
+    let mut edits = Vec::new();
+    let mut limit = lines.len();
+
+    while let Some((line_no, command)) = script.next() {
+        let command = std::str::from_utf8(command)?;
+        let (range, op) = command.split_at(command.len().saturating_sub(1));
+
+        let number = |s: &str| -> Result<usize, Error> {
+            Ok(s.parse::<usize>()
+                .with_context(|_| format_err!("invalid command {}: {:?}", line_no, command))?)
+        };
+
+        let (start, end) = match range.find(',') {
+            Some(comma) => (number(&range[..comma])?, number(&range[comma + 1..])?),
+            None => (number(range)?, number(range)?),
+        };
+
+        let (from, to) = match op {
+            "a" => (start, start),
+            "c" | "d" => {
+                ensure!(start > 0, "command {} out of range: {:?}", line_no, command);
+                (start - 1, end)
+            }
+            _ => bail!("unsupported command {}: {:?}", line_no, command),
+        };
+
+        ensure!(
+            start <= end && to <= lines.len(),
+            "command {} out of range: {:?}",
+            line_no,
+            command
+        );
+
+        ensure!(
+            to <= limit,
+            "command {} is not in descending order: {:?}",
+            line_no,
+            command
+        );
+        limit = from;
+
+        let mut text = Vec::new();
+        if "a" == op || "c" == op {
+            loop {
+                match script.next() {
+                    Some((_, b".")) => break,
+                    Some((_, line)) => text.push(line),
+                    None => bail!("unterminated text for command {}: {:?}", line_no, command),
+                }
+            }
+        }
+
+        edits.push((from, to, text));
+    }
+
+    let mut ret = Vec::with_capacity(original.len() + patch.len());
+    let mut pos = 0;
+
+    for (from, to, text) in edits.into_iter().rev() {
+        for line in lines[pos..from].iter().chain(&text) {
+            ret.extend_from_slice(line);
+            ret.push(b'\n');
+        }
+        pos = to;
+    }
+
+    for line in &lines[pos..] {
+        ret.extend_from_slice(line);
+        ret.push(b'\n');
+    }
+
+    Ok(ret)
+}
+
+fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    if data.is_empty() {
+        return Vec::new();
+    }
+    data.split(|&b| b'\n' == b).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply;
+    use super::parse_index;
+
+    #[test]
+    fn ed() {
+        let original = b"one\ntwo\nthree\nfour\nfive\n";
+        let patch = b"5a\nsix\n.\n3,4c\nTHREE\n.\n1d\n";
+        assert_eq!(
+            &b"two\nTHREE\nfive\nsix\n"[..],
+            apply(original, patch).unwrap().as_slice()
+        );
+
+        assert_eq!(
+            &b"zero\n"[..],
+            apply(b"", b"0a\nzero\n.\n").unwrap().as_slice()
+        );
+
+        assert!(apply(original, b"9d\n").is_err());
+        assert!(apply(original, b"0d\n").is_err());
+        assert!(apply(original, b"0c\nzero\n.\n").is_err());
+        assert!(apply(original, b"1d\n3d\n").is_err());
+        assert!(apply(original, b"2a\nunterminated\n").is_err());
+        assert!(apply(original, b"2x\n").is_err());
+    }
+
+    #[test]
+    fn index() {
+        let index = parse_index(
+            "SHA256-Current: 1111111111111111111111111111111111111111111111111111111111111111 300
+SHA256-History:
+ 2222222222222222222222222222222222222222222222222222222222222222 100 2019-01-01-0000.00
+ 3333333333333333333333333333333333333333333333333333333333333333 200 2019-01-02-0000.00
+SHA256-Patches:
+ 4444444444444444444444444444444444444444444444444444444444444444 10 2019-01-01-0000.00
+ 5555555555555555555555555555555555555555555555555555555555555555 20 2019-01-02-0000.00
+SHA256-Download:
+ 6666666666666666666666666666666666666666666666666666666666666666 5 2019-01-01-0000.00.gz
+ 7777777777777777777777777777777777777777777777777777777777777777 6 2019-01-02-0000.00.gz
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(vec!["2019-01-01-0000.00", "2019-01-02-0000.00"]),
+            index.patches_from(&[0x22; 32])
+        );
+        assert_eq!(
+            Some(vec!["2019-01-02-0000.00"]),
+            index.patches_from(&[0x33; 32])
+        );
+        assert_eq!(None, index.patches_from(&[0x11; 32]));
+        assert_eq!(6, index.downloads["2019-01-02-0000.00"].len);
+    }
+}
@@ -0,0 +1,390 @@
+//! Picking which version of a package to install, its _candidate_, like `apt-cache policy`,
+//! from the _Releases_ it's available from, and any [crate::preferences].
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+use deb_version::compare_versions;
+
+use crate::parse::Package;
+use crate::preferences::Pin;
+use crate::preferences::Preference;
+use crate::preferences::ReleaseField;
+use crate::system::DownloadedList;
+
+/// The priority of everything in the target release, e.g. from `APT::Default-Release`.
+const TARGET_RELEASE: i32 = 990;
+/// The usual priority of anything available.
+const AVAILABLE: i32 = 500;
+/// The priority of the installed version, and of upgrades from `ButAutomaticUpgrades` _Releases_.
+const INSTALLED: i32 = 100;
+/// The priority of anything from a `NotAutomatic` _Release_.
+const NOT_AUTOMATIC: i32 = 1;
+/// Versions older than the installed version are only candidates with at least this priority.
+const DOWNGRADE: i32 = 1000;
+
+/// Somewhere versions of packages are available from: a _Listing_, or the `dpkg` database.
+///
+/// Fields of the _Release_ which are missing are `None`; they never match a pin.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PackageFile {
+    /// The `Suite`, e.g. `stable`, or `now` for the `dpkg` database.
+    pub archive: Option<String>,
+    pub codename: Option<String>,
+    pub origin: Option<String>,
+    pub label: Option<String>,
+    pub component: Option<String>,
+    /// The version of the _Release_, e.g. `12.5`.
+    pub version: Option<String>,
+    pub arch: Option<String>,
+    /// The host the _Release_ was downloaded from, or empty for local files.
+    pub site: String,
+    pub not_automatic: bool,
+    pub but_automatic_upgrades: bool,
+    /// This is the `dpkg` database.
+    pub installed: bool,
+}
+
+impl PackageFile {
+    pub fn from_list(list: &DownloadedList) -> PackageFile {
+        let release = &list.release.file;
+        PackageFile {
+            archive: release.suite.clone(),
+            codename: release.codename.clone(),
+            origin: release.origin.clone(),
+            label: release.label.clone(),
+            component: Some(list.listing.component.to_string()).filter(|c| !c.is_empty()),
+            version: release.version.clone(),
+            arch: list.listing.arch.clone(),
+            site: list.release.req.site().to_string(),
+            not_automatic: release.not_automatic,
+            but_automatic_upgrades: release.but_automatic_upgrades,
+            installed: false,
+        }
+    }
+
+    /// The `dpkg` database, which is the `now` archive.
+    pub fn dpkg_status() -> PackageFile {
+        PackageFile {
+            archive: Some("now".to_string()),
+            installed: true,
+            ..PackageFile::default()
+        }
+    }
+
+    fn field(&self, field: ReleaseField) -> Option<&str> {
+        match field {
+            ReleaseField::Archive => self.archive.as_deref(),
+            ReleaseField::Codename => self.codename.as_deref(),
+            ReleaseField::Origin => self.origin.as_deref(),
+            ReleaseField::Label => self.label.as_deref(),
+            ReleaseField::Component => self.component.as_deref(),
+            ReleaseField::Version => self.version.as_deref(),
+            ReleaseField::Architecture => self.arch.as_deref(),
+        }
+    }
+
+    /// Whether a `release` or `origin` pin matches. `version` pins never match a file.
+    fn pinned_by(&self, pin: &Pin) -> bool {
+        match pin {
+            Pin::Release(fields) => fields.iter().all(|(field, pattern)| {
+                self.field(*field)
+                    .is_some_and(|value| pattern.matches(value))
+            }),
+            Pin::Origin(pattern) => pattern.matches(&self.site),
+            Pin::Version(_) => false,
+        }
+    }
+}
+
+/// The host, if there is one, then the fields which a `Pin: release` can match, e.g.
+/// `deb.debian.org o=Debian,a=stable,n=bookworm,l=Debian,c=main,b=amd64`.
+impl fmt::Display for PackageFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.site.is_empty() {
+            write!(f, "{} ", self.site)?;
+        }
+
+        let fields = [
+            ("v", ReleaseField::Version),
+            ("o", ReleaseField::Origin),
+            ("a", ReleaseField::Archive),
+            ("n", ReleaseField::Codename),
+            ("l", ReleaseField::Label),
+            ("c", ReleaseField::Component),
+            ("b", ReleaseField::Architecture),
+        ];
+
+        let fields: Vec<String> = fields
+            .iter()
+            .filter_map(|(key, field)| self.field(*field).map(|value| format!("{}={}", key, value)))
+            .collect();
+
+        write!(f, "{}", fields.join(","))
+    }
+}
+
+/// A version of a package, and everywhere it's available from.
+#[derive(Clone, Debug)]
+pub struct Version {
+    pub package: Package,
+    pub files: Vec<Rc<PackageFile>>,
+}
+
+impl Version {
+    pub fn is_installed(&self) -> bool {
+        self.files.iter().any(|file| file.installed)
+    }
+}
+
+/// How to pick candidates: the preferences, and the target release.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    preferences: Vec<Preference>,
+    default_release: Option<String>,
+}
+
+impl Policy {
+    pub fn new() -> Policy {
+        Policy::default()
+    }
+
+    /// Add some preferences. The first preference which matches is used, so these only
+    /// apply where the preferences already added don't.
+    pub fn add_preferences<I: IntoIterator<Item = Preference>>(&mut self, preferences: I) {
+        self.preferences.extend(preferences);
+    }
+
+    /// Prefer everything from a release, matching its suite or codename, e.g. `stable` or
+    /// `bookworm`, like `APT::Default-Release`. This takes precedence over general preferences.
+    pub fn set_default_release<S: ToString>(&mut self, release: S) {
+        self.default_release = Some(release.to_string());
+    }
+
+    /// The priority of everything available from a file, unless there's a preference for
+    /// a specific package, like `apt-cache policy` shows when given no packages.
+    pub fn file_priority(&self, file: &PackageFile) -> i32 {
+        if let Some(release) = &self.default_release {
+            let release = Some(release.as_str());
+            if release == file.archive.as_deref() || release == file.codename.as_deref() {
+                return TARGET_RELEASE;
+            }
+        }
+
+        let general = self
+            .preferences
+            .iter()
+            .filter(|preference| preference.is_general())
+            .find(|preference| file.pinned_by(&preference.pin));
+
+        if let Some(preference) = general {
+            return preference.priority;
+        }
+
+        if file.installed || (file.not_automatic && file.but_automatic_upgrades) {
+            INSTALLED
+        } else if file.not_automatic {
+            NOT_AUTOMATIC
+        } else {
+            AVAILABLE
+        }
+    }
+
+    /// The priority of a version: from the first preference for its package which matches
+    /// it, otherwise the highest priority of the files it's available from.
+    pub fn priority(&self, version: &Version) -> i32 {
+        let package = &version.package;
+        let specific = self
+            .preferences
+            .iter()
+            .filter(|preference| !preference.is_general() && preference.applies_to(&package.name))
+            .find(|preference| match &preference.pin {
+                Pin::Version(pattern) => pattern.matches(&package.version),
+                pin => version.files.iter().any(|file| file.pinned_by(pin)),
+            });
+
+        if let Some(preference) = specific {
+            return preference.priority;
+        }
+
+        version
+            .files
+            .iter()
+            .map(|file| self.file_priority(file))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Pick the version to install from all the versions of a package: the highest priority,
+    /// then the highest version.
+    ///
+    /// Versions with a negative priority are never picked, and versions older than the
+    /// installed version are only picked with a priority of at least 1000.
+    pub fn candidate<'v>(&self, versions: &'v [Version]) -> Option<&'v Version> {
+        let installed = versions.iter().find(|version| version.is_installed());
+
+        let mut best: Option<(i32, &Version)> = None;
+        for version in versions {
+            let priority = self.priority(version);
+            if priority < 0 {
+                continue;
+            }
+
+            if let Some(installed) = installed {
+                let older = Ordering::Less
+                    == compare_versions(&version.package.version, &installed.package.version);
+                if older && priority < DOWNGRADE {
+                    continue;
+                }
+            }
+
+            let better = match best {
+                None => true,
+                Some((best_priority, best)) => {
+                    priority > best_priority
+                        || (priority == best_priority
+                            && Ordering::Greater
+                                == compare_versions(
+                                    &version.package.version,
+                                    &best.package.version,
+                                ))
+                }
+            };
+
+            if better {
+                best = Some((priority, version));
+            }
+        }
+
+        best.map(|(_, version)| version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::rc::Rc;
+
+    use super::PackageFile;
+    use super::Policy;
+    use super::Version;
+    use crate::parse::test_package;
+    use crate::preferences;
+
+    fn file(archive: &str, not_automatic: bool, but_automatic_upgrades: bool) -> Rc<PackageFile> {
+        Rc::new(PackageFile {
+            archive: Some(archive.to_string()),
+            codename: Some(format!("{}-codename", archive)),
+            origin: Some("Debian".to_string()),
+            component: Some("main".to_string()),
+            site: "deb.debian.org".to_string(),
+            not_automatic,
+            but_automatic_upgrades,
+            ..PackageFile::default()
+        })
+    }
+
+    fn version(name: &str, version: &str, files: &[&Rc<PackageFile>]) -> Version {
+        Version {
+            package: test_package(name, version, ""),
+            files: files.iter().map(|file| Rc::clone(file)).collect(),
+        }
+    }
+
+    fn candidate<'v>(policy: &Policy, versions: &'v [Version]) -> &'v str {
+        policy
+            .candidate(versions)
+            .map(|version| version.package.version.as_str())
+            .unwrap_or("none")
+    }
+
+    #[test]
+    fn defaults() {
+        let stable = file("stable", false, false);
+        let backports = file("stable-backports", true, true);
+        let experimental = file("experimental", true, false);
+        let installed = Rc::new(PackageFile::dpkg_status());
+
+        let policy = Policy::new();
+        assert_eq!(500, policy.file_priority(&stable));
+        assert_eq!(100, policy.file_priority(&backports));
+        assert_eq!(1, policy.file_priority(&experimental));
+        assert_eq!(100, policy.file_priority(&installed));
+
+        let versions = [
+            version("foo", "3", &[&experimental]),
+            version("foo", "2", &[&backports]),
+            version("foo", "1", &[&stable]),
+        ];
+        assert_eq!("1", candidate(&policy, &versions));
+
+        // upgrades from backports are automatic, once installed from there
+        let versions = [
+            version("foo", "3", &[&experimental]),
+            version("foo", "2.1", &[&backports]),
+            version("foo", "2", &[&installed]),
+            version("foo", "1", &[&stable]),
+        ];
+        assert_eq!("2.1", candidate(&policy, &versions));
+
+        let mut policy = Policy::new();
+        policy.set_default_release("experimental-codename");
+        assert_eq!("3", candidate(&policy, &versions));
+    }
+
+    #[test]
+    fn pins() {
+        let stable = file("stable", false, false);
+        let testing = file("testing", false, false);
+        let installed = Rc::new(PackageFile::dpkg_status());
+
+        let mut policy = Policy::new();
+        policy.add_preferences(
+            preferences::read(io::Cursor::new(
+                r"
+Package: foo
+Pin: version 1.*
+Pin-Priority: 1001
+
+Package: bar*
+Pin: release a=testing
+Pin-Priority: -1
+
+Package: *
+Pin: release a=testing
+Pin-Priority: 400
+
+Package: *
+Pin: origin deb.debian.org
+Pin-Priority: 300
+",
+            ))
+            .unwrap(),
+        );
+
+        assert_eq!(400, policy.file_priority(&testing));
+        assert_eq!(300, policy.file_priority(&stable));
+
+        let foo = [
+            version("foo", "2", &[&installed, &testing]),
+            version("foo", "1.5", &[&stable]),
+        ];
+        assert_eq!(400, policy.priority(&foo[0]));
+        assert_eq!(1001, policy.priority(&foo[1]));
+        assert_eq!("1.5", candidate(&policy, &foo));
+
+        let bar = [
+            version("barn", "2", &[&testing]),
+            version("barn", "1", &[&stable]),
+        ];
+        assert_eq!("1", candidate(&policy, &bar));
+
+        // nothing older than the installed version, without a high enough priority
+        let baz = [
+            version("baz", "2", &[&installed]),
+            version("baz", "1", &[&testing]),
+        ];
+        assert_eq!("2", candidate(&policy, &baz));
+    }
+}
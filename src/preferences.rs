@@ -0,0 +1,354 @@
+//! Load `Preference` objects from APT preferences files (e.g. `/etc/apt/preferences`,
+//! or `/etc/apt/preferences.d/*`), which adjust the priorities of versions of packages.
+//!
+//! See `apt_preferences(5)`, and [crate::policy] for how they are used.
+
+use std::io::Read;
+
+use failure::bail;
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+use regex::Regex;
+
+use crate::rfc822;
+use crate::rfc822::RfcMapExt;
+
+/// A stanza from a preferences file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preference {
+    /// The packages this applies to. A single `*` makes it a _general_ preference,
+    /// which applies to everything from matching _Releases_.
+    pub packages: Vec<Pattern>,
+    pub pin: Pin,
+    pub priority: i32,
+}
+
+impl Preference {
+    pub fn is_general(&self) -> bool {
+        1 == self.packages.len() && "*" == self.packages[0].as_str()
+    }
+
+    pub fn applies_to(&self, name: &str) -> bool {
+        self.packages.iter().any(|pattern| pattern.matches(name))
+    }
+}
+
+/// What a [Preference] matches, from its `Pin` field.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pin {
+    /// `release a=stable, n=bookworm`: everything listed must match the _Release_.
+    Release(Vec<(ReleaseField, Pattern)>),
+    /// `origin deb.debian.org`: the host the _Release_ came from. Local files,
+    /// including the `dpkg` database, have an empty origin.
+    Origin(Pattern),
+    /// `version 1.2*`: the version of the package.
+    Version(Pattern),
+}
+
+/// The keys of a `Pin: release` expression.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReleaseField {
+    /// `a=`: the `Suite`, e.g. `stable`, or `now` for the `dpkg` database.
+    Archive,
+    /// `n=`: the `Codename`, e.g. `bookworm`.
+    Codename,
+    /// `o=`: the `Origin`, e.g. `Debian`.
+    Origin,
+    /// `l=`: the `Label`, e.g. `Debian-Security`.
+    Label,
+    /// `c=`: the component, e.g. `main`.
+    Component,
+    /// `v=`: the `Version` of the _Release_, e.g. `12.5`.
+    Version,
+    /// `b=`: the architecture of the _Listing_.
+    Architecture,
+}
+
+/// A value to match: a `/regex/`, a glob like `gnome-*`, or an exact string.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    text: String,
+    regex: Option<Regex>,
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.text == other.text
+    }
+}
+
+impl Pattern {
+    pub fn new(text: &str) -> Result<Pattern, Error> {
+        let regex = if text.len() > 1 && text.starts_with('/') && text.ends_with('/') {
+            Some(Regex::new(&text[1..text.len() - 1]))
+        } else if text.contains(['*', '?', '[']) {
+            Some(Regex::new(&glob_to_regex(text)))
+        } else {
+            None
+        };
+
+        Ok(Pattern {
+            text: text.to_string(),
+            regex: regex
+                .transpose()
+                .with_context(|_| format_err!("invalid pattern: {:?}", text))?,
+        })
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match &self.regex {
+            Some(regex) => regex.is_match(value),
+            None => self.text == value,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Translate a `fnmatch`-style glob to an anchored regex.
+fn glob_to_regex(glob: &str) -> String {
+    let mut ret = String::with_capacity(glob.len() * 2);
+    ret.push('^');
+
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => ret.push_str(".*"),
+            '?' => ret.push('.'),
+            '[' => {
+                ret.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    ret.push('^');
+                }
+                for c in chars.by_ref() {
+                    if '\\' == c || '[' == c {
+                        ret.push('\\');
+                    }
+                    ret.push(c);
+                    if ']' == c {
+                        break;
+                    }
+                }
+            }
+            c => ret.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    ret.push('$');
+    ret
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    if value.len() > 1 && value.starts_with('"') && value.ends_with('"') {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+fn parse_release(expr: &str) -> Result<Pin, Error> {
+    let expr = expr.trim();
+
+    // a bare value is a version if it looks like one, otherwise an archive
+    if !expr.contains('=') {
+        let field = if expr.starts_with(|c: char| c.is_ascii_digit()) {
+            ReleaseField::Version
+        } else {
+            ReleaseField::Archive
+        };
+        return Ok(Pin::Release(vec![(field, Pattern::new(unquote(expr))?)]));
+    }
+
+    let mut ret = Vec::new();
+    for part in expr.split(',') {
+        let (key, value) = part
+            .split_once('=')
+            .ok_or_else(|| format_err!("expected key=value, not {:?}", part))?;
+
+        let field = match key.trim() {
+            "a" => ReleaseField::Archive,
+            "n" => ReleaseField::Codename,
+            "o" => ReleaseField::Origin,
+            "l" => ReleaseField::Label,
+            "c" => ReleaseField::Component,
+            "v" => ReleaseField::Version,
+            "b" => ReleaseField::Architecture,
+            other => bail!("unrecognised release key: {:?}", other),
+        };
+
+        ret.push((field, Pattern::new(unquote(value))?));
+    }
+
+    Ok(Pin::Release(ret))
+}
+
+fn parse_pin(pin: &str) -> Result<Pin, Error> {
+    let pin = pin.trim();
+    let (kind, expr) = pin.split_once(char::is_whitespace).unwrap_or((pin, ""));
+    ensure!(!expr.trim().is_empty(), "nothing to match in {:?}", pin);
+
+    Ok(match kind {
+        "release" => parse_release(expr)?,
+        "origin" => Pin::Origin(Pattern::new(unquote(expr))?),
+        "version" => Pin::Version(Pattern::new(unquote(expr))?),
+        other => bail!("unrecognised pin type: {:?}", other),
+    })
+}
+
+fn read_stanza(block: &str) -> Result<Option<Preference>, Error> {
+    let block = block
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<&str>>()
+        .join("\n");
+
+    let mut map = rfc822::fields_in_block(&block).collect_to_map()?;
+    map.remove("Explanation");
+
+    // e.g. a stanza of only explanations
+    if map.is_empty() {
+        return Ok(None);
+    }
+
+    let packages = map
+        .remove_value("Package")
+        .split_whitespace()?
+        .iter()
+        .map(|pattern| Pattern::new(pattern))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let pin = parse_pin(map.remove_value("Pin").one_line_req()?)
+        .with_context(|_| format_err!("parsing Pin"))?;
+
+    let priority = map.remove_value("Pin-Priority").one_line_req()?;
+    let priority = priority
+        .parse::<i32>()
+        .with_context(|_| format_err!("parsing Pin-Priority: {:?}", priority))?;
+    ensure!(0 != priority, "Pin-Priority must not be zero");
+
+    Ok(Some(Preference {
+        packages,
+        pin,
+        priority,
+    }))
+}
+
+/// Read the `Preference`s from a preferences file, in order.
+pub fn read<R: Read>(from: R) -> Result<Vec<Preference>, Error> {
+    let mut ret = Vec::new();
+
+    for (no, block) in rfc822::Blocks::new(from, "preferences".to_string()).enumerate() {
+        let block = block.with_context(|_| format_err!("reading around stanza {}", no + 1))?;
+        ret.extend(read_stanza(&block).with_context(|_| format_err!("parsing stanza {}", no + 1))?);
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::read;
+    use super::Pattern;
+    use super::Pin;
+    use super::ReleaseField;
+
+    #[test]
+    fn patterns() {
+        let glob = Pattern::new("gnome-*").unwrap();
+        assert!(glob.matches("gnome-shell"));
+        assert!(!glob.matches("libgnome-2"));
+
+        let class = Pattern::new("lib[!x]?").unwrap();
+        assert!(class.matches("liba1"));
+        assert!(!class.matches("libx1"));
+
+        let regex = Pattern::new("/^lib.*-dev$/").unwrap();
+        assert!(regex.matches("libc6-dev"));
+        assert!(!regex.matches("libc6"));
+
+        let exact = Pattern::new("a.b").unwrap();
+        assert!(exact.matches("a.b"));
+        assert!(!exact.matches("axb"));
+    }
+
+    #[test]
+    fn stanzas() {
+        let prefs = read(io::Cursor::new(
+            r#"
+Explanation: prefer backports for these
+Package: gnome-* /^lib.*-dev$/
+Pin: release a=bookworm-backports, o="Debian Backports"
+Pin-Priority: 600
+
+# a comment
+Package: *
+Pin: origin deb.example.com
+Pin-Priority: -10
+
+Package: foo
+Pin: version 1.2*
+Pin-Priority: 1001
+
+Package: bar
+Pin: release stable
+Pin-Priority: 900
+"#,
+        ))
+        .unwrap();
+
+        assert_eq!(4, prefs.len());
+
+        assert_eq!(2, prefs[0].packages.len());
+        assert!(!prefs[0].is_general());
+        assert!(prefs[0].applies_to("gnome-shell"));
+        assert!(prefs[0].applies_to("libc6-dev"));
+        assert_eq!(
+            Pin::Release(vec![
+                (
+                    ReleaseField::Archive,
+                    Pattern::new("bookworm-backports").unwrap()
+                ),
+                (
+                    ReleaseField::Origin,
+                    Pattern::new("Debian Backports").unwrap()
+                ),
+            ]),
+            prefs[0].pin
+        );
+        assert_eq!(600, prefs[0].priority);
+
+        assert!(prefs[1].is_general());
+        assert_eq!(
+            Pin::Origin(Pattern::new("deb.example.com").unwrap()),
+            prefs[1].pin
+        );
+        assert_eq!(-10, prefs[1].priority);
+
+        assert_eq!(Pin::Version(Pattern::new("1.2*").unwrap()), prefs[2].pin);
+        assert_eq!(
+            Pin::Release(vec![(
+                ReleaseField::Archive,
+                Pattern::new("stable").unwrap()
+            )]),
+            prefs[3].pin
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let read = |s: &str| read(io::Cursor::new(s));
+        assert!(read("Package: foo\nPin: release x=1\nPin-Priority: 1\n").is_err());
+        assert!(read("Package: foo\nPin: sideways a\nPin-Priority: 1\n").is_err());
+        assert!(read("Package: foo\nPin: release a=b\nPin-Priority: 0\n").is_err());
+        assert!(read("Package: foo\nPin: release a=b\n").is_err());
+        assert!(read("Package: /(/\nPin: release a=b\nPin-Priority: 1\n").is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Finding what refers to a package, e.g. "what depends on libfoo", like `apt-cache rdepends`.
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use failure::bail;
+use failure::Error;
+
+use crate::parse::Dependency;
+use crate::parse::Package;
+use crate::parse::PackageType;
+
+/// A field which refers to other packages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Relation {
+    PreDepends,
+    Depends,
+    Recommends,
+    Suggests,
+    /// `Build-Depends`, `Build-Depends-Arch` or `Build-Depends-Indep`, from a source package.
+    BuildDepends,
+}
+
+impl Relation {
+    pub const ALL: [Relation; 5] = [
+        Relation::PreDepends,
+        Relation::Depends,
+        Relation::Recommends,
+        Relation::Suggests,
+        Relation::BuildDepends,
+    ];
+}
+
+impl fmt::Display for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Relation::PreDepends => "Pre-Depends",
+            Relation::Depends => "Depends",
+            Relation::Recommends => "Recommends",
+            Relation::Suggests => "Suggests",
+            Relation::BuildDepends => "Build-Depends",
+        })
+    }
+}
+
+/// The field name, in any case, e.g. `pre-depends`.
+impl FromStr for Relation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Relation, Error> {
+        match Relation::ALL
+            .iter()
+            .find(|relation| relation.to_string().eq_ignore_ascii_case(s))
+        {
+            Some(relation) => Ok(*relation),
+            None => bail!("unrecognised relationship: {:?}", s),
+        }
+    }
+}
+
+/// A package which refers to a name.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Reference {
+    /// The referring package; a source package for `Build-Depends`.
+    pub package: String,
+    pub version: String,
+    pub relation: Relation,
+    /// The name referred to: a package, or a virtual package.
+    pub target: String,
+}
+
+/// An index from names to the packages which refer to them.
+#[derive(Clone, Debug, Default)]
+pub struct ReverseDependencies {
+    referenced: HashMap<String, BTreeSet<Reference>>,
+    /// The virtual packages each package provides.
+    provides: HashMap<String, BTreeSet<String>>,
+}
+
+impl ReverseDependencies {
+    pub fn new() -> ReverseDependencies {
+        ReverseDependencies::default()
+    }
+
+    /// Index a binary or source package.
+    pub fn add(&mut self, package: &Package) {
+        let fields: Vec<(Relation, &Vec<Dependency>)> = match &package.style {
+            PackageType::Binary(bin) => {
+                for provide in &bin.provides {
+                    for alt in &provide.alternate {
+                        self.provides
+                            .entry(package.name.to_string())
+                            .or_default()
+                            .insert(alt.package.to_string());
+                    }
+                }
+
+                vec![
+                    (Relation::PreDepends, &bin.pre_depends),
+                    (Relation::Depends, &bin.depends),
+                    (Relation::Recommends, &bin.recommends),
+                    (Relation::Suggests, &bin.suggests),
+                ]
+            }
+            PackageType::Source(src) => vec![
+                (Relation::BuildDepends, &src.build_dep),
+                (Relation::BuildDepends, &src.build_dep_arch),
+                (Relation::BuildDepends, &src.build_dep_indep),
+            ],
+        };
+
+        for (relation, deps) in fields {
+            for alt in deps.iter().flat_map(|dep| &dep.alternate) {
+                self.referenced
+                    .entry(alt.package.to_string())
+                    .or_default()
+                    .insert(Reference {
+                        package: package.name.to_string(),
+                        version: package.version.to_string(),
+                        relation,
+                        target: alt.package.to_string(),
+                    });
+            }
+        }
+    }
+
+    /// Everything which refers to exactly this name, ignoring what it provides.
+    pub fn references<'s>(&'s self, name: &str) -> impl Iterator<Item = &'s Reference> {
+        self.referenced.get(name).into_iter().flatten()
+    }
+
+    /// Everything which refers to a package, or to anything it provides, through any of
+    /// the `relations`, and, up to `depth`, everything which refers to those, breadth first.
+    ///
+    /// Returns the depth each was found at, starting at `1`. Each package is only listed
+    /// from the first depth it's found at, and source packages aren't followed.
+    pub fn query(
+        &self,
+        name: &str,
+        relations: &[Relation],
+        depth: usize,
+    ) -> Vec<(usize, &Reference)> {
+        let mut ret = Vec::new();
+        // the depth each package was first found at; source packages are kept apart,
+        // as they often share a name with a binary package
+        let mut seen: HashMap<(&str, bool), usize> = HashMap::new();
+        seen.insert((name, false), 0);
+
+        let mut queue = VecDeque::new();
+        queue.push_back((1, name));
+
+        while let Some((level, name)) = queue.pop_front() {
+            if level > depth {
+                break;
+            }
+
+            let provided = self.provides.get(name).into_iter().flatten();
+            let mut found: BTreeSet<&Reference> = BTreeSet::new();
+            for target in Some(name).into_iter().chain(provided.map(|s| s.as_str())) {
+                found.extend(
+                    self.references(target)
+                        .filter(|reference| relations.contains(&reference.relation)),
+                );
+            }
+
+            for reference in found {
+                let source = Relation::BuildDepends == reference.relation;
+                match seen.get(&(reference.package.as_str(), source)) {
+                    Some(&first) if first < level => continue,
+                    Some(_) => (),
+                    None => {
+                        seen.insert((&reference.package, source), level);
+                        if !source {
+                            queue.push_back((level + 1, &reference.package));
+                        }
+                    }
+                }
+                ret.push((level, reference));
+            }
+        }
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Relation;
+    use super::ReverseDependencies;
+    use crate::parse::test_package;
+
+    fn found(index: &ReverseDependencies, relations: &[Relation], depth: usize) -> Vec<String> {
+        index
+            .query("libfoo1", relations, depth)
+            .into_iter()
+            .map(|(depth, reference)| {
+                format!(
+                    "{} {} {} {}",
+                    depth, reference.package, reference.relation, reference.target
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query() {
+        let mut index = ReverseDependencies::new();
+        index.add(&test_package("libfoo1", "1", "Provides: libfoo-abi-1\n"));
+        index.add(&test_package(
+            "foo",
+            "1",
+            "Depends: libfoo1 (>= 1), libc6\n",
+        ));
+        index.add(&test_package(
+            "bar",
+            "1",
+            "Recommends: libfoo-abi-1 | other\n",
+        ));
+        index.add(&test_package(
+            "foo-gui",
+            "1",
+            "Pre-Depends: foo\nSuggests: bar\n",
+        ));
+        index.add(&test_package("unrelated", "1", "Depends: libc6\n"));
+
+        assert_eq!(2, index.references("libc6").count());
+        assert_eq!(
+            vec!["1 bar Recommends libfoo-abi-1", "1 foo Depends libfoo1"],
+            found(&index, &Relation::ALL, 1)
+        );
+        assert_eq!(
+            vec![
+                "1 bar Recommends libfoo-abi-1",
+                "1 foo Depends libfoo1",
+                "2 foo-gui Suggests bar",
+                "2 foo-gui Pre-Depends foo",
+            ],
+            found(&index, &Relation::ALL, 2)
+        );
+        assert_eq!(
+            vec!["1 foo Depends libfoo1", "2 foo-gui Pre-Depends foo"],
+            found(&index, &[Relation::Depends, Relation::PreDepends], 5)
+        );
+
+        // `x` is only listed at the first depth, not again through `y`
+        let mut index = ReverseDependencies::new();
+        index.add(&test_package("x", "1", "Depends: libfoo1, y\n"));
+        index.add(&test_package("y", "1", "Depends: libfoo1\n"));
+        index.add(&test_package("libfoo1", "1", "Suggests: x\n"));
+        assert_eq!(
+            vec!["1 x Depends libfoo1", "1 y Depends libfoo1"],
+            found(&index, &Relation::ALL, 3)
+        );
+
+        assert_eq!(Relation::PreDepends, "pre-depends".parse().unwrap());
+        assert!("conflicts".parse::<Relation>().is_err());
+    }
+}
@@ -1,5 +1,8 @@
+//! _Release_ files, which describe the _Listings_ available in a repository.
+
 use std::collections::hash_map;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 use std::fs;
 use std::io;
@@ -8,19 +11,22 @@ use std::path::Path;
 use std::path::PathBuf;
 
 use chrono::DateTime;
+use chrono::Duration;
 use chrono::Utc;
+use failure::bail;
 use failure::ensure;
 use failure::format_err;
 use failure::Error;
+use failure::Fail;
 use failure::ResultExt;
 use gpgrv::Keyring;
 use insideout::InsideOut;
-use reqwest;
 use reqwest::Url;
 
+use crate::checksum;
 use crate::checksum::Hashes;
-use crate::fetch::fetch;
 use crate::fetch::Download;
+use crate::fetch::Fetcher;
 use crate::rfc822;
 use crate::rfc822::RfcMapExt;
 use crate::signing::GpgClient;
@@ -32,20 +38,37 @@ pub struct RequestedReleases {
 
 #[derive(Clone, PartialOrd, Ord, Hash, PartialEq, Eq, Debug)]
 pub struct RequestedRelease {
+    /// The url from the sources entry, which may be a `mirror+file:` list of mirrors.
     mirror: Url,
+    /// The actual mirrors to download from, in order of preference.
+    mirrors: Vec<Url>,
     /// This can also be called "suite" in some places,
     /// e.g. "unstable" (suite) == "sid" (codename)
     codename: String,
 
     pub arches: Vec<String>,
+
+    /// The keys allowed to sign this release, from the `signed-by` option.
+    /// If empty, any key from the global keyring is allowed.
+    pub signed_by: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ReleaseFile {
-    origin: String,
-    label: String,
-    suite: Option<String>,
-    codename: Option<String>,
+    /// Who made the _Release_, e.g. `Debian`.
+    pub origin: Option<String>,
+    /// e.g. `Debian-Security`; often the same as the `origin`.
+    pub label: Option<String>,
+    /// The class of release, e.g. `stable` or `bookworm-backports`.
+    pub suite: Option<String>,
+    /// The name of the release, e.g. `bookworm`.
+    pub codename: Option<String>,
+    /// The version of the release, e.g. `12.5`.
+    pub version: Option<String>,
+    /// Packages should not be installed from this _Release_ unless requested, e.g. `experimental`.
+    pub not_automatic: bool,
+    /// ..but upgrades to packages already installed from it are fine, e.g. backports.
+    pub but_automatic_upgrades: bool,
     changelogs: Option<String>,
     date: DateTime<Utc>,
     valid_until: Option<DateTime<Utc>>,
@@ -63,6 +86,40 @@ pub struct ReleaseContent {
     pub hashes: Hashes,
 }
 
+/// A _Release_ which verified correctly, but which should not be trusted.
+#[derive(Debug, Clone)]
+pub enum ReleaseValidityError {
+    Expired {
+        valid_until: DateTime<Utc>,
+    },
+    FromTheFuture {
+        date: DateTime<Utc>,
+    },
+    RolledBack {
+        date: DateTime<Utc>,
+        previous: DateTime<Utc>,
+    },
+}
+
+impl fmt::Display for ReleaseValidityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ReleaseValidityError::*;
+        match self {
+            Expired { valid_until } => write!(f, "release expired at {}", valid_until),
+            FromTheFuture { date } => {
+                write!(f, "release is dated {}, which is in the future", date)
+            }
+            RolledBack { date, previous } => write!(
+                f,
+                "release is dated {}, older than the previously seen {}",
+                date, previous
+            ),
+        }
+    }
+}
+
+impl Fail for ReleaseValidityError {}
+
 #[derive(Debug, Clone)]
 pub struct Release {
     pub req: RequestedRelease,
@@ -80,14 +137,92 @@ impl fmt::Debug for ReleaseContent {
     }
 }
 
+impl ReleaseFile {
+    /// Check the _Release_ is current, i.e. that it hasn't expired,
+    /// and that it doesn't claim to be from further in the future than `max_future`.
+    pub fn check_validity(
+        &self,
+        check_valid_until: bool,
+        max_future: Duration,
+    ) -> Result<(), ReleaseValidityError> {
+        let now = Utc::now();
+
+        if self.date > now + max_future {
+            return Err(ReleaseValidityError::FromTheFuture { date: self.date });
+        }
+
+        if let Some(valid_until) = self.valid_until {
+            if check_valid_until && valid_until < now {
+                return Err(ReleaseValidityError::Expired { valid_until });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `check-valid-until=no` on any of the entries disables the check for the whole _Release_.
+fn check_valid_until(entries: &[Entry]) -> bool {
+    !entries
+        .iter()
+        .any(|entry| Some(false) == entry.options.check_valid_until)
+}
+
 impl RequestedRelease {
+    /// The directory containing the `Release` file, e.g. `.../dists/sid/`.
+    ///
+    /// For _flat_ repositories, this is the directory named by the "codename" instead.
+    ///
+    /// This is on the first mirror; see [RequestedRelease::download] for the others.
     pub fn dists(&self) -> Result<Url, Error> {
-        Ok(self
-            .mirror
+        let mirror = &self.mirrors[0];
+
+        if self.is_flat() {
+            return Ok(mirror.join(&self.codename)?);
+        }
+
+        Ok(mirror
             .join("dists/")?
             .join(&format!("{}/", self.codename))?)
     }
 
+    /// The same path as `url` (which is on the first mirror), on each of the other mirrors.
+    pub fn alternatives(&self, url: &Url) -> Result<Vec<Url>, Error> {
+        let path = url
+            .as_str()
+            .strip_prefix(self.mirrors[0].as_str())
+            .ok_or_else(|| format_err!("{} isn't on the mirror {}", url, self.mirrors[0]))?;
+
+        Ok(self.mirrors[1..]
+            .iter()
+            .map(|mirror| mirror.join(path))
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Download a `url` on the first mirror, falling back to the other mirrors.
+    pub fn download<P: AsRef<Path>>(&self, url: Url, to: P) -> Result<Download, Error> {
+        let mut download = Download::from_to(url, to);
+        download.mirrors = self.alternatives(&download.from)?;
+        Ok(download)
+    }
+
+    /// The url which the `Filename`s in _Listings_ are relative to, on the first mirror.
+    ///
+    /// This is the root of the mirror, even for _flat_ repositories, whose `Filename`s
+    /// include the directory.
+    pub fn files_base(&self) -> Result<Url, Error> {
+        Ok(self.mirrors[0].clone())
+    }
+
+    pub fn is_flat(&self) -> bool {
+        self.codename.ends_with('/')
+    }
+
+    /// The host of the first mirror, e.g. `deb.debian.org`, which `Pin: origin` matches.
+    pub fn site(&self) -> &str {
+        self.mirrors[0].host_str().unwrap_or("")
+    }
+
     pub fn filesystem_safe(&self) -> String {
         let u = &self.mirror;
         let underscore_path = u
@@ -101,7 +236,7 @@ impl RequestedRelease {
             u.host_str().unwrap_or(""),
             u.port_or_known_default().unwrap_or(0),
             underscore_path,
-            self.codename
+            self.codename.replace('/', "_")
         )
     }
 
@@ -111,6 +246,14 @@ impl RequestedRelease {
             .join(format!("{}_InRelease", self.filesystem_safe()))
     }
 
+    /// Where a _Release_ is stored after signature verification, but before we've
+    /// decided whether its dates are acceptable.
+    pub fn unchecked_path<P: AsRef<Path>>(&self, lists_dir: P) -> PathBuf {
+        lists_dir
+            .as_ref()
+            .join(format!("{}_Unchecked", self.filesystem_safe()))
+    }
+
     pub fn verified_path<P: AsRef<Path>>(&self, lists_dir: P) -> PathBuf {
         lists_dir
             .as_ref()
@@ -135,10 +278,14 @@ impl RequestedReleases {
                 "urls must end with a '/': {:?}",
                 entry.url
             );
+            let mirror = Url::parse(&entry.url)?;
             match ret.entry(RequestedRelease {
-                mirror: Url::parse(&entry.url)?,
+                mirrors: resolve_mirrors(&mirror)
+                    .with_context(|_| format_err!("finding mirrors for {}", mirror))?,
+                mirror,
                 codename: entry.suite_codename.to_string(),
                 arches: arches.to_vec(),
+                signed_by: entry.options.signed_by.to_vec(),
             }) {
                 hash_map::Entry::Vacant(vacancy) => {
                     vacancy.insert(vec![entry.clone()]);
@@ -147,63 +294,96 @@ impl RequestedReleases {
             }
         }
 
+        let mut seen = HashSet::with_capacity(ret.len());
+        for release in ret.keys() {
+            ensure!(
+                seen.insert((&release.mirror, &release.codename)),
+                "conflicting signed-by values for {} {}",
+                release.mirror,
+                release.codename
+            );
+        }
+
         Ok(RequestedReleases {
             releases: ret.into_iter().collect(),
         })
     }
 
-    pub fn download<P: AsRef<Path>>(
+    /// Download and verify the _Release_ files, rejecting any which are expired, from the
+    /// future (beyond `max_future`), or older than the copy we already have.
+    pub(crate) fn download<P: AsRef<Path>>(
         &self,
         lists_dir: P,
         keyring: &Keyring,
-        client: &reqwest::Client,
+        fetcher: &Fetcher,
+        max_future: Duration,
     ) -> Result<(), Error> {
         let lists_dir = lists_dir.as_ref();
 
-        let mut gpg = GpgClient::new(keyring);
+        for &(ref release, ref entries) in &self.releases {
+            let mut gpg = GpgClient::signed_by(keyring, &release.signed_by)
+                .with_context(|_| format_err!("loading signed-by keys for {:?}", release))?;
 
-        for &(ref release, _) in &self.releases {
             let dest: PathBuf = release.download_path(lists_dir);
-            let verified = release.verified_path(lists_dir);
+            let unchecked = release.unchecked_path(lists_dir);
 
-            match fetch(
-                client,
-                &[Download::from_to(
-                    release.dists()?.join("InRelease")?,
-                    &dest,
-                )],
-            ) {
-                Ok(_) => gpg.verify_clearsigned(&dest, &verified),
+            let in_release = release.dists()?.join("InRelease")?;
+
+            let verified_url = match fetcher.fetch(&[release.download(in_release.clone(), &dest)?])
+            {
+                Ok(_) => gpg
+                    .verify_clearsigned(&dest, &unchecked)
+                    .map(|()| in_release),
                 Err(_) => {
                     let mut detatched_signature = dest.as_os_str().to_os_string();
                     detatched_signature.push(".gpg");
 
-                    fetch(
-                        client,
-                        &[Download::from_to(release.dists()?.join("Release")?, &dest)],
-                    )?;
-
-                    fetch(
-                        client,
-                        &[Download::from_to(
-                            release.dists()?.join("Release.gpg")?,
-                            &detatched_signature,
-                        )],
-                    )?;
-                    gpg.verify_detached(&dest, detatched_signature, verified)
+                    let release_url = release.dists()?.join("Release")?;
+                    fetcher.fetch(&[release.download(release_url.clone(), &dest)?])?;
+
+                    fetcher.fetch(&[release.download(
+                        release.dists()?.join("Release.gpg")?,
+                        &detatched_signature,
+                    )?])?;
+                    gpg.verify_detached(&dest, detatched_signature, &unchecked)
+                        .map(|()| release_url)
                 }
             }
             .with_context(|_| format_err!("verifying {:?} at {:?}", release, dest))?;
+
+            fetcher.progress.verified(&verified_url);
+
+            let verified = release.verified_path(lists_dir);
+
+            check_dates(
+                &unchecked,
+                &verified,
+                check_valid_until(entries),
+                max_future,
+            )
+            .with_context(|_| format_err!("checking dates of {:?}", release))?;
+
+            fs::rename(&unchecked, &verified)
+                .with_context(|_| format_err!("storing verified release {:?}", verified))?;
         }
 
         Ok(())
     }
 
-    pub fn parse<P: AsRef<Path>>(self, lists_dir: P) -> Result<Vec<Release>, Error> {
+    /// Load the previously downloaded and verified _Release_ files,
+    /// rejecting any which have since expired.
+    pub fn parse<P: AsRef<Path>>(
+        self,
+        lists_dir: P,
+        max_future: Duration,
+    ) -> Result<Vec<Release>, Error> {
         self.releases
             .into_iter()
             .map(|(req, sources_entries)| {
-                parse_release_file(req.verified_path(&lists_dir)).map(|file| Release {
+                let file = parse_release_file(req.verified_path(&lists_dir))?;
+                file.check_validity(check_valid_until(&sources_entries), max_future)
+                    .with_context(|_| format_err!("checking dates of {:?}", req))?;
+                Ok(Release {
                     req,
                     file,
                     sources_entries,
@@ -213,6 +393,62 @@ impl RequestedReleases {
     }
 }
 
+/// A `mirror+file:` url names a local file listing mirrors, one per line; the first is
+/// preferred, and the rest are tried in order if it fails. Other urls are their own mirror.
+fn resolve_mirrors(url: &Url) -> Result<Vec<Url>, Error> {
+    match url.scheme() {
+        "mirror+file" | "mirror+copy" => (),
+        "mirror" | "mirror+http" | "mirror+https" => {
+            bail!("unsupported: remote mirror lists: {}", url)
+        }
+        _ => return Ok(vec![url.clone()]),
+    }
+
+    // the sources list parser adds a trailing slash, which isn't part of the file name
+    let path = url.path().trim_end_matches('/');
+    let list = fs::read_to_string(path).with_context(|_| format_err!("reading {:?}", path))?;
+
+    let mirrors = list
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|mirror| !mirror.starts_with('#'))
+        .map(|mirror| {
+            if mirror.ends_with('/') {
+                Url::parse(mirror)
+            } else {
+                Url::parse(&format!("{}/", mirror))
+            }
+        })
+        .collect::<Result<Vec<Url>, _>>()?;
+
+    ensure!(!mirrors.is_empty(), "no mirrors listed in {:?}", path);
+
+    Ok(mirrors)
+}
+
+fn check_dates<P: AsRef<Path>, Q: AsRef<Path>>(
+    unchecked: P,
+    verified: Q,
+    check_valid_until: bool,
+    max_future: Duration,
+) -> Result<(), Error> {
+    let file = parse_release_file(unchecked)?;
+    file.check_validity(check_valid_until, max_future)?;
+
+    if verified.as_ref().exists() {
+        let previous = parse_release_file(verified)?;
+        if file.date < previous.date {
+            return Err(ReleaseValidityError::RolledBack {
+                date: file.date,
+                previous: previous.date,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
 pub fn parse_release_file<P: AsRef<Path>>(path: P) -> Result<ReleaseFile, Error> {
     let mut file = String::with_capacity(100 * 1024);
     io::BufReader::new(
@@ -228,10 +464,14 @@ pub fn parse_release_file<P: AsRef<Path>>(path: P) -> Result<ReleaseFile, Error>
 fn parse_release(release: &str) -> Result<ReleaseFile, Error> {
     let mut data = rfc822::fields_in_block(release).collect_to_map()?;
     Ok(ReleaseFile {
-        origin: data.remove_value("Origin").one_line_req()?.to_string(),
-        label: data.remove_value("Label").one_line_req()?.to_string(),
+        // these are frequently missing from flat repositories
+        origin: data.remove_value("Origin").one_line_owned()?,
+        label: data.remove_value("Label").one_line_owned()?,
         suite: data.remove_value("Suite").one_line_owned()?,
         codename: data.remove_value("Codename").one_line_owned()?,
+        version: data.remove_value("Version").one_line_owned()?,
+        not_automatic: yes_no_or_false(&mut data, "NotAutomatic")?,
+        but_automatic_upgrades: yes_no_or_false(&mut data, "ButAutomaticUpgrades")?,
         changelogs: data.remove_value("Changelogs").one_line_owned()?,
         date: rfc822::parse_date(&data.remove_value("Date").one_line_req()?)?,
         valid_until: data
@@ -239,43 +479,81 @@ fn parse_release(release: &str) -> Result<ReleaseFile, Error> {
             .one_line()?
             .map(|s| rfc822::parse_date(&s))
             .inside_out()?,
-        acquire_by_hash: data
-            .remove_value("Acquire-By-Hash")
-            .one_line()?
-            .map(|s| "yes" == s)
-            .unwrap_or(false),
-        arches: data.remove_value("Architectures").split_whitespace()?,
-        components: data.remove_value("Components").split_whitespace()?,
+        acquire_by_hash: yes_no_or_false(&mut data, "Acquire-By-Hash")?,
+        arches: split_whitespace_or_empty(&mut data, "Architectures")?,
+        components: split_whitespace_or_empty(&mut data, "Components")?,
         description: data.remove_value("Description").one_line_owned()?,
         contents: load_contents(&mut data)?,
     })
 }
 
+fn yes_no_or_false(data: &mut rfc822::Map, key: &str) -> Result<bool, Error> {
+    Ok(data
+        .remove_value(key)
+        .one_line()?
+        .map(|s| "yes" == s)
+        .unwrap_or(false))
+}
+
+fn split_whitespace_or_empty(data: &mut rfc822::Map, key: &str) -> Result<Vec<String>, Error> {
+    if data.contains_key(key) {
+        data.remove_value(key).split_whitespace()
+    } else {
+        Ok(Vec::new())
+    }
+}
+
 fn load_contents(data: &mut HashMap<&str, Vec<&str>>) -> Result<Vec<ReleaseContent>, Error> {
-    let md5s = take_checksums(data, "MD5Sum")?;
-    let sha256s = take_checksums(data, "SHA256")?
-        .ok_or_else(|| format_err!("sha256sums missing from release file; refusing to process"))?;
+    let all = take_hashes(
+        data,
+        &ChecksumFields {
+            md5: "MD5Sum",
+            sha1: "SHA1",
+            sha256: "SHA256",
+            sha512: "SHA512",
+        },
+    )?;
+
+    // which hashes are good enough is decided by the `HashPolicy` when each file is downloaded
+    Ok(all
+        .into_iter()
+        .map(|((name, len), hashes)| ReleaseContent {
+            len,
+            name: name.to_string(),
+            hashes,
+        })
+        .collect())
+}
 
-    let mut ret = Vec::with_capacity(sha256s.len());
+/// The names of the fields listing each type of hash, e.g. `MD5Sum` or `Checksums-Sha256`.
+pub(crate) struct ChecksumFields {
+    pub md5: &'static str,
+    pub sha1: &'static str,
+    pub sha256: &'static str,
+    pub sha512: &'static str,
+}
 
-    for (key, hash) in sha256s {
-        let (name, len) = key;
+/// Gather all of the hashes listed for each file, by name and size.
+pub(crate) fn take_hashes<'a>(
+    data: &mut HashMap<&str, Vec<&'a str>>,
+    fields: &ChecksumFields,
+) -> Result<HashMap<(&'a str, u64), Hashes>, Error> {
+    let mut ret: HashMap<(&'a str, u64), Hashes> = HashMap::new();
 
-        let mut md5 = [0u8; 16];
+    for (key, hash) in take_checksums(data, fields.md5)?.unwrap_or_default() {
+        ret.entry(key).or_default().md5 = Some(checksum::parse_md5(hash)?);
+    }
 
-        if let Some(md5s) = md5s.as_ref() {
-            if let Some(hash) = md5s.get(&key) {
-                md5 = crate::checksum::parse_md5(hash)?;
-            }
-        }
+    for (key, hash) in take_checksums(data, fields.sha1)?.unwrap_or_default() {
+        ret.entry(key).or_default().sha1 = Some(checksum::parse_sha1(hash)?);
+    }
 
-        let sha256 = crate::checksum::parse_sha256(hash)?;
+    for (key, hash) in take_checksums(data, fields.sha256)?.unwrap_or_default() {
+        ret.entry(key).or_default().sha256 = Some(checksum::parse_sha256(hash)?);
+    }
 
-        ret.push(ReleaseContent {
-            len,
-            name: name.to_string(),
-            hashes: Hashes { md5, sha256 },
-        })
+    for (key, hash) in take_checksums(data, fields.sha512)?.unwrap_or_default() {
+        ret.entry(key).or_default().sha512 = Some(checksum::parse_sha512(hash)?);
     }
 
     Ok(ret)
@@ -301,3 +579,112 @@ fn parse_checksums<'s>(lines: &[&'s str]) -> Result<HashMap<(&'s str, u64), &'s
 
     Ok(ret)
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use chrono::Utc;
+
+    use reqwest::Url;
+
+    use super::parse_release;
+    use super::resolve_mirrors;
+    use super::ReleaseValidityError;
+    use super::RequestedRelease;
+
+    #[test]
+    fn mirror_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("mirrors.txt");
+        std::fs::write(
+            &list,
+            "# preferred\nhttp://one/debian/\n\nhttp://two/debian\tpriority:2\n",
+        )
+        .unwrap();
+
+        let url = Url::parse(&format!("mirror+file:{}/", list.display())).unwrap();
+        let mirrors = resolve_mirrors(&url).unwrap();
+        assert_eq!(
+            vec!["http://one/debian/", "http://two/debian/"],
+            mirrors.iter().map(|u| u.as_str()).collect::<Vec<_>>()
+        );
+
+        let plain = Url::parse("http://deb.debian.org/debian/").unwrap();
+        assert_eq!(vec![plain.clone()], resolve_mirrors(&plain).unwrap());
+    }
+
+    #[test]
+    fn flat_files_base() {
+        let mirror = Url::parse("http://host/repo/").unwrap();
+        let release = RequestedRelease {
+            mirror: mirror.clone(),
+            mirrors: vec![mirror],
+            codename: "sub/".to_string(),
+            arches: vec!["amd64".to_string()],
+            signed_by: Vec::new(),
+        };
+
+        assert!(release.is_flat());
+        assert_eq!("http://host/repo/sub/", release.dists().unwrap().as_str());
+        assert_eq!(
+            "http://host/repo/sub/foo_1_all.deb",
+            release
+                .files_base()
+                .unwrap()
+                .join("sub/foo_1_all.deb")
+                .unwrap()
+                .as_str()
+        );
+    }
+
+    fn release_dated(date: &str, valid_until: &str) -> String {
+        format!(
+            "Origin: Debian
+Label: Debian
+Date: {}
+Valid-Until: {}
+Architectures: amd64
+Components: main
+SHA256:
+ 0000000000000000000000000000000000000000000000000000000000000000 0 main/Contents
+",
+            date, valid_until
+        )
+    }
+
+    #[test]
+    fn validity() {
+        let format = |date: chrono::DateTime<Utc>| date.to_rfc2822();
+        let now = Utc::now();
+        let skew = Duration::minutes(10);
+
+        let current = parse_release(&release_dated(
+            &format(now - Duration::days(1)),
+            &format(now + Duration::days(1)),
+        ))
+        .unwrap();
+        current.check_validity(true, skew).unwrap();
+
+        let expired = parse_release(&release_dated(
+            &format(now - Duration::days(2)),
+            &format(now - Duration::days(1)),
+        ))
+        .unwrap();
+        match expired.check_validity(true, skew) {
+            Err(ReleaseValidityError::Expired { .. }) => (),
+            other => panic!("unexpected: {:?}", other),
+        }
+        expired.check_validity(false, skew).unwrap();
+
+        let future = parse_release(&release_dated(
+            &format(now + Duration::days(1)),
+            &format(now + Duration::days(2)),
+        ))
+        .unwrap();
+        match future.check_validity(true, skew) {
+            Err(ReleaseValidityError::FromTheFuture { .. }) => (),
+            other => panic!("unexpected: {:?}", other),
+        }
+        future.check_validity(true, Duration::days(2)).unwrap();
+    }
+}
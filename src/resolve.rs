@@ -0,0 +1,1071 @@
+//! Working out which binary _Packages_ to install or remove to satisfy some requests,
+//! like `apt-get install --simulate`.
+//!
+//! Multi-Arch is not considered: each package name can be installed at most once.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::slice;
+use std::sync::OnceLock;
+
+use deb_version::compare_versions;
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+use failure::Fail;
+
+use crate::parse::Constraint;
+use crate::parse::ConstraintOperator;
+use crate::parse::Dependency;
+use crate::parse::Package;
+use crate::parse::SingleDependency;
+
+/// Give up on problems which would take longer than this many decisions to solve.
+const MAX_STEPS: usize = 100_000;
+
+/// Something the user wants to change about the installed system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// Make sure something satisfying this is installed, e.g. `foo`, `foo (>= 2)`,
+    /// or a virtual package.
+    Install(SingleDependency),
+    /// Make sure something satisfying any of the alternatives is installed, e.g. `foo | bar`.
+    Satisfy(Dependency),
+    /// Make sure nothing satisfying this is installed, e.g. for `Build-Conflicts`.
+    Avoid(SingleDependency),
+    /// Make sure no version of this package is installed.
+    Remove(String),
+}
+
+impl Request {
+    /// The alternatives, one of which must be installed.
+    fn wanted(&self) -> &[SingleDependency] {
+        match self {
+            Request::Install(dep) => slice::from_ref(dep),
+            Request::Satisfy(dep) => &dep.alternate,
+            Request::Avoid(_) | Request::Remove(_) => &[],
+        }
+    }
+}
+
+/// The changes needed to satisfy some [Request]s.
+///
+/// An upgrade appears as the new version in `install`, and the old version in `remove`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Plan<'u> {
+    pub install: Vec<&'u Package>,
+    pub remove: Vec<&'u Package>,
+}
+
+/// The requests can't be satisfied, and why, starting from the request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unsatisfiable {
+    pub reasons: Vec<String>,
+}
+
+impl fmt::Display for Unsatisfiable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unable to satisfy requests: {}", self.reasons.join(", "))
+    }
+}
+
+impl Fail for Unsatisfiable {}
+
+/// All the binary _Packages_ which are known, either installed or available to install.
+#[derive(Clone, Debug, Default)]
+pub struct Universe {
+    candidates: Vec<Candidate>,
+    /// Candidates for each name, highest version first.
+    by_name: HashMap<String, Vec<usize>>,
+    /// Candidates providing each virtual package, in the order they were added.
+    providers: HashMap<String, Vec<usize>>,
+    /// Candidates with Conflicts or Breaks on each name.
+    conflicted_by: HashMap<String, Vec<usize>>,
+    /// Candidates with Pre-Depends or Depends on each name.
+    depended_on_by: HashMap<String, Vec<usize>>,
+    installed: Vec<usize>,
+    /// See [Universe::broken]; found when first needed.
+    broken: OnceLock<Vec<Option<usize>>>,
+}
+
+#[derive(Clone, Debug)]
+struct Candidate {
+    package: Package,
+    installed: bool,
+}
+
+/// A possible set of installed packages. This is changed in place during the search,
+/// recording each change, so it can be undone when backtracking.
+#[derive(Default)]
+struct State<'u> {
+    chosen: BTreeMap<&'u str, usize>,
+    /// Candidates which have been replaced or removed, and may not return.
+    rejected: HashSet<usize>,
+    /// Candidates which haven't been checked for problems since they were chosen. Adding a
+    /// candidate can't cause problems for the others which won't be found by checking the
+    /// new one, but removing one can, so anything depending on it is checked again.
+    unsettled: Vec<usize>,
+    trail: Vec<Change<'u>>,
+}
+
+/// A change to a [State], and what is needed to undo it.
+enum Change<'u> {
+    Chose(&'u str, Option<usize>),
+    Removed(&'u str, usize),
+    Rejected(usize),
+    Settled(usize),
+    Unsettled,
+}
+
+/// A way to fix a problem.
+#[derive(Copy, Clone, Debug)]
+enum Action {
+    /// Install the candidate, replacing any other version.
+    Choose(usize),
+    Remove(usize),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Problem {
+    /// The request, by index, isn't satisfied.
+    Requested(usize),
+    /// The dependency, by index in `Universe::depends`, of the candidate isn't satisfied.
+    Depends(usize, usize),
+    /// The first candidate conflicts with, or breaks, the second.
+    Conflicts(usize, usize),
+}
+
+impl Problem {
+    fn is_request(&self) -> bool {
+        matches!(self, Problem::Requested(_))
+    }
+}
+
+/// A problem the search is trying to fix, and the ways left to try.
+struct Level {
+    problem: Problem,
+    /// How long the trail was before trying anything.
+    checkpoint: usize,
+    /// The fixes left to try, last first.
+    fixes: Vec<Action>,
+    fallback: Option<Action>,
+    trying_fallback: bool,
+    first_failure: Option<Failure>,
+}
+
+impl Level {
+    /// Why nothing worked.
+    fn failure(self) -> Failure {
+        match self.first_failure {
+            // another request failed, after this problem was fixed; it's not the cause
+            Some(failure) if failure.problems.last().is_some_and(|p| p.is_request()) => failure,
+            Some(mut failure) => {
+                failure.problems.push(self.problem);
+                failure
+            }
+            // nothing could fix it; the fallback's failure isn't interesting
+            None => Failure {
+                problems: vec![self.problem],
+                gave_up: false,
+            },
+        }
+    }
+}
+
+/// Why a search failed: the chain of problems which couldn't be fixed, from the last one
+/// found back to the one the search started from, or that it gave up.
+struct Failure {
+    problems: Vec<Problem>,
+    gave_up: bool,
+}
+
+struct Search<'u> {
+    universe: &'u Universe,
+    requests: &'u [Request],
+    forbidden: HashSet<&'u str>,
+    /// Problems with the installed system before any requests were considered; left alone.
+    baseline: HashSet<Problem>,
+    steps: usize,
+}
+
+impl Universe {
+    pub fn new() -> Universe {
+        Universe::default()
+    }
+
+    /// Add a _Package_ which may be installed, e.g. from a _Listing_.
+    pub fn add_available(&mut self, package: Package) -> Result<(), Error> {
+        self.add(package, false)
+    }
+
+    /// Add a _Package_ which is currently installed, e.g. from the `dpkg` database.
+    pub fn add_installed(&mut self, package: Package) -> Result<(), Error> {
+        self.add(package, true)
+    }
+
+    fn add(&mut self, package: Package, installed: bool) -> Result<(), Error> {
+        let bin = package.as_bin().ok_or_else(|| {
+            format_err!(
+                "{} {} isn't a binary package",
+                package.name,
+                package.version
+            )
+        })?;
+
+        self.broken.take();
+
+        let versions = self.by_name.entry(package.name.to_string()).or_default();
+
+        // the same version is often in multiple _Listings_, and also installed
+        let mut position = versions.len();
+        for (pos, &existing) in versions.iter().enumerate() {
+            let existing = &mut self.candidates[existing];
+            match compare_versions(&package.version, &existing.package.version) {
+                Ordering::Equal => {
+                    if installed && !existing.installed {
+                        existing.installed = true;
+                        self.installed.push(versions[pos]);
+                    }
+                    return Ok(());
+                }
+                Ordering::Greater => {
+                    position = pos;
+                    break;
+                }
+                Ordering::Less => (),
+            }
+        }
+
+        let idx = self.candidates.len();
+        versions.insert(position, idx);
+
+        for provided in bin.provides.iter().flat_map(|dep| &dep.alternate) {
+            self.providers
+                .entry(provided.package.to_string())
+                .or_default()
+                .push(idx);
+        }
+
+        let conflicts = bin.conflicts.iter().chain(&bin.breaks);
+        for conflict in conflicts.flat_map(|dep| &dep.alternate) {
+            self.conflicted_by
+                .entry(conflict.package.to_string())
+                .or_default()
+                .push(idx);
+        }
+
+        let depends = bin.pre_depends.iter().chain(&bin.depends);
+        for dep in depends.flat_map(|dep| &dep.alternate) {
+            let dependers = self
+                .depended_on_by
+                .entry(dep.package.to_string())
+                .or_default();
+            // e.g. `foo (>= 1), foo (<< 2)`
+            if dependers.last() != Some(&idx) {
+                dependers.push(idx);
+            }
+        }
+
+        if installed {
+            self.installed.push(idx);
+        }
+
+        self.candidates.push(Candidate { package, installed });
+        Ok(())
+    }
+
+    /// All the _Packages_, in the order they were first added.
+    pub fn packages(&self) -> impl Iterator<Item = &Package> {
+        self.candidates.iter().map(|candidate| &candidate.package)
+    }
+
+    /// Check whether this exact version of `package` can be installed, alongside the
+    /// installed packages, if there are any, like `dose-distcheck`.
+    ///
+    /// Fails with [Unsatisfiable] if it can't.
+    pub fn check_installable(&self, package: &Package) -> Result<(), Error> {
+        let exact = SingleDependency {
+            package: package.name.to_string(),
+            version_constraints: vec![Constraint::new(ConstraintOperator::Eq, &package.version)],
+            ..SingleDependency::default()
+        };
+
+        self.plan(&[Request::Install(exact)])?;
+        Ok(())
+    }
+
+    /// Find the changes needed to satisfy all of the `requests`, keeping everything else
+    /// that is installed, where possible.
+    ///
+    /// Fails with [Unsatisfiable] if there is no way to satisfy the requests.
+    pub fn plan(&self, requests: &[Request]) -> Result<Plan<'_>, Error> {
+        let mut state = State::default();
+        for &idx in &self.installed {
+            let name = self.candidates[idx].package.name.as_str();
+            ensure!(
+                state.chosen.insert(name, idx).is_none(),
+                "multiple versions of {} are installed",
+                name
+            );
+        }
+
+        let mut search = Search {
+            universe: self,
+            requests: &[],
+            forbidden: HashSet::new(),
+            baseline: HashSet::new(),
+            steps: 0,
+        };
+
+        search.baseline = state
+            .chosen
+            .values()
+            .flat_map(|&idx| search.problems_of(&state, idx))
+            .collect();
+        search.requests = requests;
+
+        for request in requests {
+            if let Request::Remove(name) = request {
+                ensure!(
+                    self.by_name.contains_key(name),
+                    "can't remove {}: package is unknown",
+                    name
+                );
+                search.forbidden.insert(name);
+                if let Some(idx) = state.chosen.remove(name.as_str()) {
+                    state.rejected.insert(idx);
+                }
+            }
+        }
+        state.unsettled = state.chosen.values().cloned().collect();
+
+        search.solve(&mut state).map_err(|failure| Unsatisfiable {
+            reasons: search.reasons(&failure),
+        })?;
+        let chosen: HashSet<usize> = state.chosen.values().cloned().collect();
+
+        let mut plan = Plan {
+            install: chosen
+                .iter()
+                .map(|&idx| &self.candidates[idx])
+                .filter(|candidate| !candidate.installed)
+                .map(|candidate| &candidate.package)
+                .collect(),
+            remove: self
+                .installed
+                .iter()
+                .filter(|idx| !chosen.contains(idx))
+                .map(|&idx| &self.candidates[idx].package)
+                .collect(),
+        };
+
+        plan.install.sort_by(|a, b| a.name.cmp(&b.name));
+        plan.remove.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(plan)
+    }
+
+    /// The candidates which satisfy `dep`: real packages, highest version first,
+    /// then packages providing it.
+    fn matching<'s>(&'s self, dep: &'s SingleDependency) -> impl Iterator<Item = usize> + 's {
+        let real = self.by_name.get(&dep.package).into_iter().flatten();
+        let virt = self.providers.get(&dep.package).into_iter().flatten();
+        real.chain(virt)
+            .cloned()
+            .filter(move |&idx| self.satisfies(idx, dep))
+    }
+
+    fn satisfies(&self, idx: usize, dep: &SingleDependency) -> bool {
+        let package = &self.candidates[idx].package;
+        if package.name == dep.package
+            && dep
+                .version_constraints
+                .iter()
+                .all(|c| c.satisfied_by(&package.version))
+        {
+            return true;
+        }
+
+        let provides = package
+            .as_bin()
+            .into_iter()
+            .flat_map(|bin| &bin.provides)
+            .flat_map(|dep| &dep.alternate)
+            .filter(|provided| provided.package == dep.package);
+
+        for provided in provides {
+            if dep.version_constraints.is_empty() {
+                return true;
+            }
+
+            // only a versioned provide, e.g. `foo (= 2)`, satisfies a versioned dependency
+            let version = provided
+                .version_constraints
+                .iter()
+                .find(|c| ConstraintOperator::Eq == c.operator)
+                .map(|c| &c.version);
+
+            if let Some(version) = version {
+                if dep
+                    .version_constraints
+                    .iter()
+                    .all(|c| c.satisfied_by(version))
+                {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Pre-Depends and Depends, which are treated the same, as we don't order installation.
+    fn depends(&self, idx: usize) -> impl Iterator<Item = &Dependency> {
+        self.candidates[idx]
+            .package
+            .as_bin()
+            .into_iter()
+            .flat_map(|bin| bin.pre_depends.iter().chain(&bin.depends))
+    }
+
+    /// Conflicts and Breaks, which are treated the same, as we don't order installation.
+    fn conflicts(&self, idx: usize) -> impl Iterator<Item = &SingleDependency> {
+        self.candidates[idx]
+            .package
+            .as_bin()
+            .into_iter()
+            .flat_map(|bin| bin.conflicts.iter().chain(&bin.breaks))
+            .flat_map(|dep| &dep.alternate)
+    }
+
+    /// Whether `a` conflicts with, or breaks, `b`. Packages never conflict with themselves,
+    /// or other versions of themselves, even through something they provide.
+    fn conflicts_with(&self, a: usize, b: usize) -> bool {
+        self.candidates[a].package.name != self.candidates[b].package.name
+            && self.conflicts(a).any(|dep| self.satisfies(b, dep))
+    }
+
+    /// For each candidate, a dependency, by index in [Universe::depends], which nothing
+    /// installable satisfies, if there is one. Conflicts aren't considered, so these are only
+    /// the candidates which could never be installed. Installed candidates are left alone, as
+    /// problems they already have are tolerated.
+    fn broken(&self) -> &[Option<usize>] {
+        self.broken.get_or_init(|| {
+            let mut broken = vec![None; self.candidates.len()];
+            loop {
+                let mut changed = false;
+                for idx in 0..self.candidates.len() {
+                    if broken[idx].is_some() || self.candidates[idx].installed {
+                        continue;
+                    }
+
+                    broken[idx] = self.depends(idx).position(|dep| {
+                        !dep.alternate
+                            .iter()
+                            .flat_map(|alt| self.matching(alt))
+                            .any(|other| broken[other].is_none())
+                    });
+                    changed |= broken[idx].is_some();
+                }
+
+                if !changed {
+                    return broken;
+                }
+            }
+        })
+    }
+
+    fn describe(&self, idx: usize) -> String {
+        let package = &self.candidates[idx].package;
+        format!("{} {}", package.name, package.version)
+    }
+}
+
+impl<'u> State<'u> {
+    fn satisfied(&self, universe: &Universe, dep: &SingleDependency) -> bool {
+        universe.matching(dep).any(|idx| self.has(universe, idx))
+    }
+
+    fn has(&self, universe: &Universe, idx: usize) -> bool {
+        Some(&idx)
+            == self
+                .chosen
+                .get(universe.candidates[idx].package.name.as_str())
+    }
+
+    fn apply(&mut self, universe: &'u Universe, action: Action) {
+        match action {
+            Action::Choose(idx) => {
+                let name = universe.candidates[idx].package.name.as_str();
+                let old = self.chosen.insert(name, idx);
+                self.trail.push(Change::Chose(name, old));
+                self.unsettle(idx);
+                if let Some(old) = old {
+                    self.reject(old);
+                    self.recheck(universe, old);
+                }
+            }
+            Action::Remove(idx) => {
+                let name = universe.candidates[idx].package.name.as_str();
+                self.chosen.remove(name);
+                self.trail.push(Change::Removed(name, idx));
+                self.reject(idx);
+                self.recheck(universe, idx);
+            }
+        }
+    }
+
+    fn unsettle(&mut self, idx: usize) {
+        self.unsettled.push(idx);
+        self.trail.push(Change::Unsettled);
+    }
+
+    fn reject(&mut self, idx: usize) {
+        if self.rejected.insert(idx) {
+            self.trail.push(Change::Rejected(idx));
+        }
+    }
+
+    /// Check again the chosen candidates which may have depended on `gone`.
+    fn recheck(&mut self, universe: &Universe, gone: usize) {
+        let package = &universe.candidates[gone].package;
+        let names = Some(&package.name).into_iter().chain(provided(package));
+        for name in names {
+            for &idx in universe.depended_on_by.get(name).into_iter().flatten() {
+                if self.has(universe, idx) {
+                    self.unsettle(idx);
+                }
+            }
+        }
+    }
+
+    /// Mark the next unsettled candidate as having no problems.
+    fn settle(&mut self) {
+        if let Some(idx) = self.unsettled.pop() {
+            self.trail.push(Change::Settled(idx));
+        }
+    }
+
+    /// Undo changes until only `checkpoint` changes remain.
+    fn undo(&mut self, checkpoint: usize) {
+        while self.trail.len() > checkpoint {
+            match self.trail.pop().expect("trail is longer than checkpoint") {
+                Change::Chose(name, Some(old)) => {
+                    self.chosen.insert(name, old);
+                }
+                Change::Chose(name, None) => {
+                    self.chosen.remove(name);
+                }
+                Change::Removed(name, idx) => {
+                    self.chosen.insert(name, idx);
+                }
+                Change::Rejected(idx) => {
+                    self.rejected.remove(&idx);
+                }
+                Change::Settled(idx) => self.unsettled.push(idx),
+                Change::Unsettled => {
+                    self.unsettled.pop();
+                }
+            }
+        }
+    }
+}
+
+impl<'u> Search<'u> {
+    /// Depth-first search, leaving `state` as the first consistent state found, or
+    /// returning why there isn't one. The search can go as deep as there are packages, so
+    /// this keeps its own stack, instead of recursing.
+    ///
+    /// Every step adds a candidate to the state, or rejects one, so this terminates.
+    fn solve(&mut self, state: &mut State<'u>) -> Result<(), Failure> {
+        let mut levels: Vec<Level> = Vec::new();
+
+        loop {
+            self.steps += 1;
+            if self.steps > MAX_STEPS {
+                return Err(Failure {
+                    problems: Vec::new(),
+                    gave_up: true,
+                });
+            }
+
+            let problem = match self.next_problem(state) {
+                Some(problem) => problem,
+                None => return Ok(()),
+            };
+
+            let (mut fixes, fallback) = self.options(state, problem);
+            fixes.reverse();
+            levels.push(Level {
+                problem,
+                checkpoint: state.trail.len(),
+                fixes,
+                fallback,
+                trying_fallback: false,
+                first_failure: None,
+            });
+
+            // find the deepest level with something left to try, and try it
+            loop {
+                let level = levels
+                    .last_mut()
+                    .expect("the first level's failure is returned");
+
+                if let Some(action) = level.fixes.pop() {
+                    state.apply(self.universe, action);
+                    break;
+                }
+
+                if let Some(action) = level.fallback.take() {
+                    level.trying_fallback = true;
+                    state.apply(self.universe, action);
+                    break;
+                }
+
+                let level = levels.pop().expect("just checked");
+                let failure = level.failure();
+                match levels.last_mut() {
+                    Some(parent) => {
+                        state.undo(parent.checkpoint);
+                        if !parent.trying_fallback {
+                            parent.first_failure.get_or_insert(failure);
+                        }
+                    }
+                    None => return Err(failure),
+                }
+            }
+        }
+    }
+
+    /// Explain each problem in the chain, and why the last one found couldn't be fixed.
+    fn reasons(&self, failure: &Failure) -> Vec<String> {
+        if failure.gave_up {
+            return vec![format!("gave up after {} decisions", MAX_STEPS)];
+        }
+
+        let mut reasons: Vec<String> = failure
+            .problems
+            .iter()
+            .rev()
+            .map(|&problem| self.explain(problem))
+            .collect();
+        if let Some(&last) = failure.problems.first() {
+            reasons.extend(self.dead_end(last));
+        }
+        reasons
+    }
+
+    /// The first problem which isn't in the baseline, settling any candidates without problems.
+    fn next_problem(&self, state: &mut State<'u>) -> Option<Problem> {
+        let universe = self.universe;
+
+        for (i, request) in self.requests.iter().enumerate() {
+            let unsatisfied = match request {
+                Request::Install(_) | Request::Satisfy(_) => !request
+                    .wanted()
+                    .iter()
+                    .any(|dep| state.satisfied(universe, dep)),
+                Request::Avoid(dep) => state.satisfied(universe, dep),
+                Request::Remove(_) => false,
+            };
+
+            if unsatisfied {
+                return Some(Problem::Requested(i));
+            }
+        }
+
+        while let Some(&idx) = state.unsettled.last() {
+            // it may have been replaced or removed since
+            let problem = self
+                .problems_of(state, idx)
+                .find(|problem| !self.baseline.contains(problem))
+                .filter(|_| state.has(universe, idx));
+
+            if problem.is_some() {
+                return problem;
+            }
+
+            state.settle();
+        }
+
+        None
+    }
+
+    /// The problems involving a chosen candidate: its dependencies, and conflicts either way.
+    fn problems_of<'s>(
+        &'s self,
+        state: &'s State,
+        idx: usize,
+    ) -> impl Iterator<Item = Problem> + 's {
+        let universe = self.universe;
+
+        let depends = universe
+            .depends(idx)
+            .enumerate()
+            .filter(move |(_, dep)| {
+                !dep.alternate
+                    .iter()
+                    .any(|alt| state.satisfied(universe, alt))
+            })
+            .map(move |(i, _)| Problem::Depends(idx, i));
+
+        let conflicts = universe
+            .conflicts(idx)
+            .flat_map(move |dep| universe.matching(dep))
+            .filter(move |&other| state.has(universe, other))
+            .filter(move |&other| universe.conflicts_with(idx, other))
+            .map(move |other| Problem::Conflicts(idx, other));
+
+        let candidate = &universe.candidates[idx].package;
+        let names = Some(&candidate.name).into_iter().chain(provided(candidate));
+
+        let conflicted = names
+            .flat_map(move |name| universe.conflicted_by.get(name).into_iter().flatten())
+            .cloned()
+            .filter(move |&other| state.has(universe, other))
+            .filter(move |&other| universe.conflicts_with(other, idx))
+            .map(move |other| Problem::Conflicts(other, idx));
+
+        depends.chain(conflicts).chain(conflicted)
+    }
+
+    /// The actions to try to fix `problem`, most preferred first, and an action to fall
+    /// back to, which avoids the problem instead of fixing it.
+    fn options(&self, state: &State<'u>, problem: Problem) -> (Vec<Action>, Option<Action>) {
+        let universe = self.universe;
+        let broken = universe.broken();
+        let allowed = |idx: usize| {
+            broken[idx].is_none()
+                && !state.rejected.contains(&idx)
+                && !state.has(universe, idx)
+                && !self
+                    .forbidden
+                    .contains(universe.candidates[idx].package.name.as_str())
+        };
+
+        let mut options = Vec::new();
+        let mut fallback = None;
+
+        match problem {
+            Problem::Requested(i) => {
+                for dep in self.requests[i].wanted() {
+                    for idx in universe.matching(dep).filter(|&idx| allowed(idx)) {
+                        options.push(Action::Choose(idx));
+                    }
+                }
+
+                if let Request::Avoid(dep) = &self.requests[i] {
+                    // another version which doesn't match, then removing it
+                    for found in universe
+                        .matching(dep)
+                        .filter(|&idx| state.has(universe, idx))
+                    {
+                        let name = &universe.candidates[found].package.name;
+                        for &version in &universe.by_name[name] {
+                            if allowed(version) && !universe.satisfies(version, dep) {
+                                options.push(Action::Choose(version));
+                            }
+                        }
+                        if self.removable(found) {
+                            options.push(Action::Remove(found));
+                        }
+                    }
+                }
+            }
+            Problem::Depends(idx, i) => {
+                let dep = universe
+                    .depends(idx)
+                    .nth(i)
+                    .expect("problem index is valid");
+                for alt in &dep.alternate {
+                    for other in universe.matching(alt).filter(|&other| allowed(other)) {
+                        options.push(Action::Choose(other));
+                    }
+                }
+                if self.removable(idx) {
+                    fallback = Some(Action::Remove(idx));
+                }
+            }
+            Problem::Conflicts(idx, other) => {
+                // another version of either, which doesn't conflict, then removing either
+                for (replace, keep) in &[(other, idx), (idx, other)] {
+                    let name = &universe.candidates[*replace].package.name;
+                    for &version in &universe.by_name[name] {
+                        if allowed(version)
+                            && !universe.conflicts_with(version, *keep)
+                            && !universe.conflicts_with(*keep, version)
+                        {
+                            options.push(Action::Choose(version));
+                        }
+                    }
+                }
+                for &remove in &[other, idx] {
+                    if self.removable(remove) {
+                        options.push(Action::Remove(remove));
+                    }
+                }
+            }
+        }
+
+        (options, fallback)
+    }
+
+    /// Essential packages are only removed if explicitly requested.
+    fn removable(&self, idx: usize) -> bool {
+        let candidate = &self.universe.candidates[idx];
+        !(candidate.installed
+            && candidate
+                .package
+                .as_bin()
+                .map(|bin| bin.essential)
+                .unwrap_or(false))
+    }
+
+    fn explain(&self, problem: Problem) -> String {
+        let universe = self.universe;
+        match problem {
+            Problem::Requested(i) => match &self.requests[i] {
+                Request::Install(dep) => format!("installing {} was requested", describe(dep)),
+                Request::Satisfy(dep) => {
+                    format!("installing {} was requested", describe_alternates(dep))
+                }
+                Request::Avoid(dep) => format!("avoiding {} was requested", describe(dep)),
+                Request::Remove(name) => format!("removing {} was requested", name),
+            },
+            Problem::Depends(idx, i) => format!(
+                "{} depends on {}",
+                universe.describe(idx),
+                describe_alternates(universe.depends(idx).nth(i).expect("valid problem"))
+            ),
+            Problem::Conflicts(idx, other) => format!(
+                "{} conflicts with {}",
+                universe.describe(idx),
+                universe.describe(other)
+            ),
+        }
+    }
+
+    /// Why a problem has no fixes.
+    fn dead_end(&self, problem: Problem) -> Vec<String> {
+        let universe = self.universe;
+        let deps: Vec<&SingleDependency> = match problem {
+            Problem::Requested(i) => match &self.requests[i] {
+                Request::Avoid(_) => return vec!["but it can't be replaced or removed".to_string()],
+                request => request.wanted().iter().collect(),
+            },
+            Problem::Depends(idx, i) => universe
+                .depends(idx)
+                .nth(i)
+                .expect("valid problem")
+                .alternate
+                .iter()
+                .collect(),
+            Problem::Conflicts(..) => {
+                return vec!["and neither can be replaced or removed".to_string()]
+            }
+        };
+
+        let matching: Vec<usize> = deps.iter().flat_map(|dep| universe.matching(dep)).collect();
+        let broken = universe.broken();
+        match matching.first() {
+            None => vec!["but no available package satisfies that".to_string()],
+            Some(&first) if matching.iter().all(|&idx| broken[idx].is_some()) => {
+                self.why_broken(first)
+            }
+            Some(_) => vec!["but every package satisfying that has been ruled out".to_string()],
+        }
+    }
+
+    /// Why a candidate could never be installed: the chain of dependencies leading to one
+    /// which no available package satisfies.
+    fn why_broken(&self, mut idx: usize) -> Vec<String> {
+        let universe = self.universe;
+        let mut reasons = Vec::new();
+        while let Some(i) = universe.broken()[idx] {
+            reasons.push(self.explain(Problem::Depends(idx, i)));
+
+            // everything satisfying it is broken, for a reason found earlier, so this ends
+            let dep = universe.depends(idx).nth(i).expect("valid dependency");
+            match dep
+                .alternate
+                .iter()
+                .flat_map(|alt| universe.matching(alt))
+                .next()
+            {
+                Some(other) => idx = other,
+                None => {
+                    reasons.push("but no available package satisfies that".to_string());
+                    break;
+                }
+            }
+        }
+        reasons
+    }
+}
+
+/// The names of the virtual packages a package provides.
+fn provided(package: &Package) -> impl Iterator<Item = &String> {
+    package
+        .as_bin()
+        .into_iter()
+        .flat_map(|bin| &bin.provides)
+        .flat_map(|dep| &dep.alternate)
+        .map(|provided| &provided.package)
+}
+
+/// Format a dependency like it would appear in a _Listing_, e.g. `foo (>= 2)`.
+fn describe(dep: &SingleDependency) -> String {
+    let mut ret = dep.package.to_string();
+    for constraint in &dep.version_constraints {
+        let op = match constraint.operator {
+            ConstraintOperator::Ge => ">=",
+            ConstraintOperator::Eq => "=",
+            ConstraintOperator::Le => "<=",
+            ConstraintOperator::Gt => ">>",
+            ConstraintOperator::Lt => "<<",
+        };
+        ret.push_str(&format!(" ({} {})", op, constraint.version));
+    }
+    ret
+}
+
+fn describe_alternates(dep: &Dependency) -> String {
+    dep.alternate
+        .iter()
+        .map(describe)
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use failure::Error;
+
+    use super::Request;
+    use super::Universe;
+    use super::Unsatisfiable;
+    use crate::parse::test_package;
+    use crate::parse::Package;
+    use crate::parse::SingleDependency;
+
+    fn install(name: &str) -> Request {
+        Request::Install(SingleDependency {
+            package: name.to_string(),
+            ..SingleDependency::default()
+        })
+    }
+
+    fn names(packages: &[&Package]) -> Vec<String> {
+        packages
+            .iter()
+            .map(|p| format!("{} {}", p.name, p.version))
+            .collect()
+    }
+
+    #[test]
+    fn depends() -> Result<(), Error> {
+        let mut universe = Universe::new();
+        universe.add_available(test_package("a", "1", "Depends: missing | b, v (>= 2)\n"))?;
+        universe.add_available(test_package("b", "1", "Pre-Depends: c\n"))?;
+        universe.add_available(test_package("b", "2", "Pre-Depends: c\n"))?;
+        universe.add_available(test_package("c", "1", ""))?;
+        universe.add_available(test_package("old", "1", "Provides: v (= 1)\n"))?;
+        universe.add_available(test_package("unversioned", "1", "Provides: v\n"))?;
+        universe.add_available(test_package("new", "1", "Provides: v (= 2)\n"))?;
+
+        let plan = universe.plan(&[install("a")])?;
+        assert_eq!(vec!["a 1", "b 2", "c 1", "new 1"], names(&plan.install));
+        assert!(plan.remove.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn conflicts() -> Result<(), Error> {
+        let mut universe = Universe::new();
+        universe.add_installed(test_package("exim", "1", "Provides: mta\nConflicts: mta\n"))?;
+        universe.add_installed(test_package("mailer", "1", "Depends: mta\n"))?;
+        universe.add_installed(test_package("lib", "1", ""))?;
+        universe.add_available(test_package("lib", "2", ""))?;
+        universe.add_available(test_package(
+            "postfix",
+            "1",
+            "Provides: mta\nConflicts: mta\n",
+        ))?;
+        universe.add_available(test_package("tool", "1", "Breaks: lib (<< 2)\n"))?;
+
+        let plan = universe.plan(&[install("postfix"), install("tool")])?;
+        assert_eq!(vec!["lib 2", "postfix 1", "tool 1"], names(&plan.install));
+        assert_eq!(vec!["exim 1", "lib 1"], names(&plan.remove));
+        Ok(())
+    }
+
+    #[test]
+    fn remove() -> Result<(), Error> {
+        let mut universe = Universe::new();
+        universe.add_installed(test_package("a", "1", "Depends: b\n"))?;
+        universe.add_installed(test_package("b", "1", ""))?;
+        universe.add_installed(test_package("c", "1", ""))?;
+
+        let plan = universe.plan(&[Request::Remove("b".to_string())])?;
+        assert!(plan.install.is_empty());
+        assert_eq!(vec!["a 1", "b 1"], names(&plan.remove));
+        Ok(())
+    }
+
+    #[test]
+    fn installable() -> Result<(), Error> {
+        let mut universe = Universe::new();
+        universe.add_available(test_package("a", "1", "Depends: b\n"))?;
+        universe.add_available(test_package("a", "2", "Depends: c\n"))?;
+        universe.add_available(test_package("b", "1", "Conflicts: d\n"))?;
+        universe.add_available(test_package("d", "1", "Depends: b\n"))?;
+
+        let broken: Vec<String> = universe
+            .packages()
+            .filter(|package| universe.check_installable(package).is_err())
+            .map(|package| format!("{} {}", package.name, package.version))
+            .collect();
+
+        assert_eq!(vec!["a 2", "d 1"], broken);
+        Ok(())
+    }
+
+    #[test]
+    fn deep() -> Result<(), Error> {
+        let mut universe = Universe::new();
+        for i in 0..10_000 {
+            universe.add_available(test_package(
+                &format!("p{}", i),
+                "1",
+                &format!("Depends: p{}\n", i + 1),
+            ))?;
+        }
+        universe.add_available(test_package("p10000", "1", ""))?;
+
+        let plan = universe.plan(&[install("p0")])?;
+        assert_eq!(10_001, plan.install.len());
+        Ok(())
+    }
+
+    #[test]
+    fn unsatisfiable() -> Result<(), Error> {
+        let mut universe = Universe::new();
+        universe.add_available(test_package("a", "1", "Depends: b (>= 2)\n"))?;
+        universe.add_available(test_package("b", "1", ""))?;
+
+        let err = universe.plan(&[install("a")]).unwrap_err();
+        let err = err.downcast::<Unsatisfiable>()?;
+        assert_eq!(
+            vec![
+                "installing a was requested",
+                "a 1 depends on b (>= 2)",
+                "but no available package satisfies that",
+            ],
+            err.reasons
+        );
+        Ok(())
+    }
+}
@@ -116,19 +116,26 @@ impl<R: Read> Iterator for ByteBlocks<R> {
         let mut buf = Vec::with_capacity(8 * 1024);
 
         // while can read non-blank lines, stuff them in the buf
-        while match self.from.read_until(b'\n', &mut buf) {
-            Ok(size) => size,
-            Err(e) => return Some(Err(e.into())),
-        } > 1
-        {}
+        loop {
+            let start = buf.len();
+            match self.from.read_until(b'\n', &mut buf) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(e) => return Some(Err(e.into())),
+            }
+
+            // a blank line ends a block, unless we haven't started one yet
+            if b"\n" == &buf[start..] {
+                buf.truncate(start);
+                if !buf.is_empty() {
+                    break;
+                }
+            }
+        }
 
         if buf.is_empty() {
             None
         } else {
-            // double new-line on the end, from a normal parse
-            if b'\n' == buf[buf.len() - 2] {
-                buf.pop();
-            }
             Some(Ok(buf))
         }
     }
@@ -314,6 +321,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn walkies_extra_blank_lines() {
+        use super::ByteBlocks;
+        use std::io;
+
+        let parts: Result<Vec<Vec<u8>>, Error> =
+            ByteBlocks::new(io::Cursor::new(b"\nfoo\n\n\nbaz\n\n"), String::new()).collect();
+        assert_eq!(vec![b"foo\n".to_vec(), b"baz\n".to_vec()], parts.unwrap());
+    }
+
     #[test]
     fn date_parsing_seriously_it_is_2019() {
         use chrono::Datelike;
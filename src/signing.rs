@@ -1,20 +1,86 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fs;
 use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
 use std::path::Path;
 
+use failure::bail;
+use failure::ensure;
 use failure::format_err;
 use failure::Error;
 use failure::ResultExt;
+use gpgrv::Digestable;
+use gpgrv::HashAlg;
 use gpgrv::Keyring;
+use gpgrv::Signature;
 use tempfile_fast::PersistableTempFile;
 
+/// A key id, i.e. the last eight bytes of a fingerprint.
+type KeyId = [u8; 8];
+
 pub struct GpgClient<'k> {
-    keyring: &'k Keyring,
+    keyring: Cow<'k, Keyring>,
+    /// If present, only signatures from these keys are accepted.
+    issuers: Option<HashSet<KeyId>>,
 }
 
 impl<'k> GpgClient<'k> {
     pub fn new(keyring: &Keyring) -> GpgClient {
-        GpgClient { keyring }
+        GpgClient {
+            keyring: Cow::Borrowed(keyring),
+            issuers: None,
+        }
+    }
+
+    /// Restrict the accepted keys according to a `signed-by` option.
+    ///
+    /// Each item can be a keyring file, an inline armoured key, or a fingerprint.
+    /// If any keyrings are provided, they replace the global keyring entirely.
+    /// If any fingerprints are provided, only signatures made directly by those keys
+    /// are accepted; a primary key's fingerprint does not cover signatures from its subkeys.
+    ///
+    /// An empty `signed_by` falls back to the global keyring.
+    pub fn signed_by(global: &'k Keyring, signed_by: &[String]) -> Result<GpgClient<'k>, Error> {
+        let mut keyring = None;
+        let mut issuers = None;
+
+        for item in signed_by {
+            if let Some(key_id) = parse_fingerprint(item)? {
+                issuers.get_or_insert_with(HashSet::new).insert(key_id);
+                continue;
+            }
+
+            let keyring = keyring.get_or_insert_with(Keyring::new);
+
+            if item.starts_with("-----BEGIN") {
+                keyring
+                    .append_keys_from_armoured(io::Cursor::new(item))
+                    .with_context(|_| format_err!("loading inline key"))?;
+                continue;
+            }
+
+            let path = Path::new(item);
+            let file = io::BufReader::new(
+                fs::File::open(path).with_context(|_| format_err!("opening keyring {:?}", path))?,
+            );
+
+            if Some("asc".as_ref()) == path.extension() {
+                keyring.append_keys_from_armoured(file)
+            } else {
+                keyring.append_keys_from(file)
+            }
+            .with_context(|_| format_err!("loading keyring {:?}", path))?;
+        }
+
+        let mut client = GpgClient::new(global);
+        if let Some(keyring) = keyring {
+            client.keyring = Cow::Owned(keyring);
+        }
+        client.issuers = issuers;
+        Ok(client)
     }
 
     pub fn verify_clearsigned<P: AsRef<Path>, Q: AsRef<Path>>(
@@ -30,7 +96,7 @@ impl<'k> GpgClient<'k> {
         )
         .with_context(|_| format_err!("creating temporary file"))?;
 
-        gpgrv::verify_message(io::BufReader::new(from), &to, &self.keyring)?;
+        self.verify_clearsigned_into(io::BufReader::new(from), &*to)?;
 
         to.persist_by_rename(dest)
             .map_err(|e| e.error)
@@ -39,21 +105,132 @@ impl<'k> GpgClient<'k> {
         Ok(())
     }
 
+    /// Check a clearsigned document, writing the signed content to `to`.
+    ///
+    /// Content is written before the signature is checked; it must be discarded on failure.
+    pub fn verify_clearsigned_into<R: BufRead, W: Write>(
+        &self,
+        from: R,
+        to: W,
+    ) -> Result<(), Error> {
+        match self.issuers {
+            None => gpgrv::verify_message(from, to, &self.keyring)?,
+            Some(_) => {
+                let doc = gpgrv::read_doc(from, io::BufWriter::new(to))?;
+                let body = doc
+                    .body
+                    .ok_or_else(|| format_err!("document wasn't a message"))?;
+                let signatures = self.allowed(
+                    doc.signatures
+                        .into_iter()
+                        .filter(|sig| body.sig_type == sig.sig_type),
+                )?;
+                gpgrv::any_signature_valid(&self.keyring, &signatures, &body.digest)
+                    .map_err(|errors| format_err!("no valid signatures: {:?}", errors))?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn verify_detached<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
         &mut self,
         file: P,
         signature: Q,
         dest: R,
     ) -> Result<(), Error> {
-        gpgrv::verify_detached(
-            io::BufReader::new(
-                fs::File::open(signature)
-                    .with_context(|_| format_err!("opening signature file"))?,
-            ),
-            fs::File::open(file.as_ref()).with_context(|_| format_err!("opening input file"))?,
-            &self.keyring,
-        )?;
+        let signature = io::BufReader::new(
+            fs::File::open(signature).with_context(|_| format_err!("opening signature file"))?,
+        );
+        let mut message =
+            fs::File::open(file.as_ref()).with_context(|_| format_err!("opening input file"))?;
+
+        match self.issuers {
+            None => gpgrv::verify_detached(signature, message, &self.keyring)?,
+            Some(_) => {
+                let doc = gpgrv::read_doc(signature, io::sink())?;
+                ensure!(doc.body.is_none(), "detached signature was a message");
+                let signatures = self.allowed(doc.signatures.into_iter())?;
+
+                let hash_alg = signatures[0].hash_alg;
+                ensure!(
+                    signatures.iter().all(|sig| hash_alg == sig.hash_alg),
+                    "unsupported: signatures with multiple hash types"
+                );
+
+                let mut digest = match hash_alg {
+                    HashAlg::Sha1 => Digestable::sha1(),
+                    HashAlg::Sha256 => Digestable::sha256(),
+                    HashAlg::Sha512 => Digestable::sha512(),
+                    other => bail!("unsupported: hash type {:?}", other),
+                };
+
+                let mut buf = [0u8; 8 * 1024];
+                loop {
+                    let read = message.read(&mut buf)?;
+                    if 0 == read {
+                        break;
+                    }
+                    digest.process(&buf[..read]);
+                }
+
+                gpgrv::any_signature_valid(&self.keyring, &signatures, &digest)
+                    .map_err(|errors| format_err!("no valid signatures: {:?}", errors))?;
+            }
+        }
+
         fs::copy(file, dest)?;
         Ok(())
     }
+
+    fn allowed<I: Iterator<Item = Signature>>(
+        &self,
+        signatures: I,
+    ) -> Result<Vec<Signature>, Error> {
+        let issuers = self.issuers.as_ref().expect("only called when restricted");
+        let signatures: Vec<Signature> = signatures
+            .filter(|sig| match sig.issuer {
+                Some(issuer) => issuers.contains(&issuer),
+                None => false,
+            })
+            .collect();
+        ensure!(
+            !signatures.is_empty(),
+            "no signatures from the keys allowed by signed-by"
+        );
+        Ok(signatures)
+    }
+}
+
+/// Fingerprints (or long key ids) are hex, and may have a trailing `!`, meaning "exactly this key".
+fn parse_fingerprint(item: &str) -> Result<Option<KeyId>, Error> {
+    let hex = item.trim_end_matches('!');
+    if ![16, 40].contains(&hex.len()) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+
+    let mut key_id = [0u8; 8];
+    key_id.copy_from_slice(&hex::decode(&hex[hex.len() - 16..])?);
+    Ok(Some(key_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_fingerprint;
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(
+            Some([0xDC, 0x30, 0xD7, 0xC2, 0x3C, 0xBB, 0xAB, 0xEE]),
+            parse_fingerprint("DC30D7C23CBBABEE").unwrap()
+        );
+        assert_eq!(
+            Some([0x64, 0x8A, 0xCF, 0xD6, 0x22, 0xF3, 0xD1, 0x38]),
+            parse_fingerprint("0146DC6D4A0B2914BDED34DB648ACFD622F3D138!").unwrap()
+        );
+        assert_eq!(
+            None,
+            parse_fingerprint("/usr/share/keyrings/debian-archive-keyring.gpg").unwrap()
+        );
+    }
 }
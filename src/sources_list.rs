@@ -1,20 +1,197 @@
-//! Load `Entry` objects from from a _classic_ sources list. (e.g. `/etc/*apt/sources.list`).
+//! Load `Entry` objects from from a _classic_ sources list. (e.g. `/etc/*apt/sources.list`),
+//! or from a `deb822`-style sources file (e.g. `/etc/apt/sources.list.d/*.sources`).
 
 use std::io::BufRead;
+use std::io::Read;
 
 use failure::bail;
+use failure::ensure;
 use failure::format_err;
 use failure::Error;
 use failure::ResultExt;
 
+use crate::rfc822;
+use crate::rfc822::RfcMapExt;
+
 /// Our representation of a classic sources list entry.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Entry {
     pub src: bool,
     pub url: String,
+    /// For a _flat_ repository, this is a path ending in `/`, e.g. `./`
     pub suite_codename: String,
     pub components: Vec<String>,
-    pub arch: Option<String>,
+    pub options: Options,
+}
+
+impl Entry {
+    /// A _flat_ repository has its `Release` and listings directly in a directory,
+    /// instead of under `dists/`, and has no components.
+    pub fn is_flat(&self) -> bool {
+        self.suite_codename.ends_with('/')
+    }
+}
+
+/// The options from the `[...]` section of a classic sources list line,
+/// or the equivalent fields of a `deb822`-style sources file.
+///
+/// Lists are empty, and flags are `None`, if the option was not specified.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Options {
+    /// `arch=`: fetch listings for these architectures, instead of the configured ones.
+    pub arches: ListOption,
+    /// `lang=`: only fetch translations for these languages.
+    pub langs: ListOption,
+    /// `target=`: only fetch these types of index.
+    pub targets: ListOption,
+    /// `signed-by=`: keyring files, or fingerprints of keys, allowed to sign this source.
+    pub signed_by: Vec<String>,
+    /// `trusted=`: skip, or force, signature verification.
+    pub trusted: Option<bool>,
+    /// `check-valid-until=`: whether to reject a _Release_ which has expired.
+    pub check_valid_until: Option<bool>,
+    /// `by-hash=`: whether to use `by-hash` urls.
+    pub by_hash: Option<ByHash>,
+    /// `pdiffs=`: whether to attempt incremental updates of listings.
+    pub pdiffs: Option<bool>,
+}
+
+/// A list-valued option, e.g. `arch=amd64,i386`, which replaces the default list,
+/// or `arch+=armhf` and `arch-=i386`, which adjust it.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ListOption {
+    /// From `=`; the default is used if this is empty.
+    pub set: Vec<String>,
+    /// From `+=`.
+    pub add: Vec<String>,
+    /// From `-=`.
+    pub remove: Vec<String>,
+}
+
+impl ListOption {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.add.is_empty() && self.remove.is_empty()
+    }
+
+    /// The values, given the default list, e.g. the configured architectures.
+    pub fn apply(&self, default: &[String]) -> Vec<String> {
+        let mut ret = if self.set.is_empty() {
+            default.to_vec()
+        } else {
+            self.set.clone()
+        };
+
+        for value in &self.add {
+            if !ret.contains(value) {
+                ret.push(value.to_string());
+            }
+        }
+
+        ret.retain(|value| !self.remove.contains(value));
+        ret
+    }
+}
+
+/// The `by-hash` option, which is a tri-state.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ByHash {
+    /// Use `by-hash` urls if the _Release_ says they are available.
+    Yes,
+    /// Never use `by-hash` urls.
+    No,
+    /// Use `by-hash` urls even if the _Release_ doesn't mention them.
+    Force,
+}
+
+impl Options {
+    /// Apply `key=values`, where the key may end in `+` or `-`, for `key+=values`.
+    fn set(&mut self, key: &str, values: Vec<String>) -> Result<(), Error> {
+        let list = match key.trim_end_matches(['+', '-']) {
+            "arch" => Some(&mut self.arches),
+            "lang" => Some(&mut self.langs),
+            "target" => Some(&mut self.targets),
+            _ => None,
+        };
+
+        if let Some(list) = list {
+            if key.ends_with('+') {
+                list.add.extend(values);
+            } else if key.ends_with('-') {
+                list.remove.extend(values);
+            } else {
+                list.set = values;
+            }
+            return Ok(());
+        }
+
+        ensure!(
+            !key.ends_with('+') && !key.ends_with('-'),
+            "only lists can be added to, or removed from: {:?}",
+            key
+        );
+
+        match key {
+            "signed-by" => self.signed_by = values,
+            "trusted" => self.trusted = Some(yes_no(&values)?),
+            "check-valid-until" => self.check_valid_until = Some(yes_no(&values)?),
+            "by-hash" => {
+                self.by_hash = Some(match single(&values)? {
+                    "force" => ByHash::Force,
+                    _ => {
+                        if yes_no(&values)? {
+                            ByHash::Yes
+                        } else {
+                            ByHash::No
+                        }
+                    }
+                })
+            }
+            "pdiffs" => self.pdiffs = Some(yes_no(&values)?),
+            // like apt, ignore options we don't understand, e.g. `allow-insecure` or `snapshot`
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+fn single(values: &[String]) -> Result<&str, Error> {
+    ensure!(
+        1 == values.len(),
+        "expected exactly one value: {:?}",
+        values
+    );
+    Ok(&values[0])
+}
+
+fn yes_no(values: &[String]) -> Result<bool, Error> {
+    match single(values)? {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        other => bail!("invalid value for yes/no: {:?}", other),
+    }
+}
+
+/// Parse the inside of the `[...]` section, e.g. `arch=amd64,i386 signed-by=/foo.gpg`.
+fn parse_options(options: &str) -> Result<Options, Error> {
+    let mut ret = Options::default();
+
+    for option in options.split_whitespace() {
+        let equals = option
+            .find('=')
+            .ok_or_else(|| format_err!("option must be key=value: {:?}", option))?;
+        let (key, values) = option.split_at(equals);
+        let values = values[1..]
+            .split(',')
+            .filter(|value| !value.is_empty())
+            .map(|value| value.to_string())
+            .collect();
+
+        ret.set(key, values)
+            .with_context(|_| format_err!("processing option {:?}", option))?;
+    }
+
+    Ok(ret)
 }
 
 fn read_single_line(line: &str) -> Result<Vec<Entry>, Error> {
@@ -28,20 +205,24 @@ fn read_single_line(line: &str) -> Result<Vec<Entry>, Error> {
         return Ok(Vec::new());
     }
 
-    let mut parts = line.split_whitespace().peekable();
+    let (src, rest) = match line.find(char::is_whitespace) {
+        Some(space) => line.split_at(space),
+        None => bail!("unexpected end of line looking for options or url"),
+    };
 
-    let src = parts
-        .next()
-        .ok_or_else(|| format_err!("deb{{,s,-src}} section required"))?;
-    let arch = match parts.peek() {
-        Some(&val) if val.starts_with("[") => {
-            parts.next();
-            Some(val)
-        }
-        Some(_) => None,
-        None => bail!("unexpected end of line looking for arch or url"),
+    let rest = rest.trim_start();
+
+    let (options, rest) = if rest.starts_with('[') {
+        let end = rest
+            .find(']')
+            .ok_or_else(|| format_err!("unterminated options section: {:?}", rest))?;
+        (parse_options(&rest[1..end])?, &rest[end + 1..])
+    } else {
+        (Options::default(), rest)
     };
 
+    let mut parts = rest.split_whitespace();
+
     let url = parts
         .next()
         .ok_or_else(|| format_err!("url section required"))?;
@@ -51,32 +232,45 @@ fn read_single_line(line: &str) -> Result<Vec<Entry>, Error> {
 
     let components: Vec<&str> = parts.collect();
 
-    let srcs: &[bool] = match src {
-        "deb" => &[false],
-        "deb-src" => &[true],
-        "debs" => &[false, true],
-        other => bail!("unsupported deb-src tag: {:?}", other),
-    };
+    ensure!(
+        !suite.ends_with('/') || components.is_empty(),
+        "flat repositories (with a suite ending in '/') must not have components"
+    );
+
+    let srcs = parse_type(src)?;
 
     let mut ret = Vec::with_capacity(srcs.len());
 
     for src in srcs {
         ret.push(Entry {
             src: *src,
-            url: if url.ends_with('/') {
-                url.to_string()
-            } else {
-                format!("{}/", url)
-            },
+            url: with_trailing_slash(url),
             suite_codename: suite.to_string(),
             components: components.iter().map(|x| x.to_string()).collect(),
-            arch: arch.map(|arch| arch.to_string()),
+            options: options.clone(),
         });
     }
 
     Ok(ret)
 }
 
+fn parse_type(src: &str) -> Result<&'static [bool], Error> {
+    Ok(match src {
+        "deb" => &[false],
+        "deb-src" => &[true],
+        "debs" => &[false, true],
+        other => bail!("unsupported deb-src tag: {:?}", other),
+    })
+}
+
+fn with_trailing_slash(url: &str) -> String {
+    if url.ends_with('/') {
+        url.to_string()
+    } else {
+        format!("{}/", url)
+    }
+}
+
 fn read_single_line_number(line: &str, no: usize) -> Result<Vec<Entry>, Error> {
     Ok(read_single_line(line).with_context(|_| format_err!("parsing line {}", no + 1))?)
 }
@@ -96,12 +290,128 @@ pub fn read<R: BufRead>(from: R) -> Result<Vec<Entry>, Error> {
         .collect())
 }
 
+fn read_stanza(block: &str) -> Result<Vec<Entry>, Error> {
+    let block = block
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<&str>>()
+        .join("\n");
+
+    if block.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut map = rfc822::fields_in_block(&block).collect_to_map()?;
+
+    match map.remove_value("Enabled").one_line()? {
+        Some("yes") | None => (),
+        Some("no") => return Ok(Vec::new()),
+        Some(other) => bail!("invalid value for Enabled: {:?}", other),
+    }
+
+    let types = map.remove_value("Types").split_whitespace()?;
+    let urls = map.remove_value("URIs").split_whitespace()?;
+    let suites = map.remove_value("Suites").split_whitespace()?;
+
+    let components = if map.contains_key("Components") {
+        map.remove_value("Components").split_whitespace()?
+    } else {
+        Vec::new()
+    };
+
+    ensure!(
+        components.is_empty() || suites.iter().all(|suite| !suite.ends_with('/')),
+        "flat repositories (with a suite ending in '/') must not have components"
+    );
+
+    let mut options = Options::default();
+
+    // An inline, armoured key, using the ` .` convention for blank lines
+    if let Some(lines) = map.get("Signed-By") {
+        if lines.len() > 1 && lines[0].starts_with("-----BEGIN") {
+            let key = lines
+                .iter()
+                .map(|line| if "." == *line { "" } else { line })
+                .collect::<Vec<&str>>()
+                .join("\n");
+            options.signed_by.push(key);
+            map.remove("Signed-By");
+        }
+    }
+
+    for (field, key) in &[
+        ("Architectures", "arch"),
+        ("Architectures-Add", "arch+"),
+        ("Architectures-Remove", "arch-"),
+        ("Languages", "lang"),
+        ("Languages-Add", "lang+"),
+        ("Languages-Remove", "lang-"),
+        ("Targets", "target"),
+        ("Targets-Add", "target+"),
+        ("Targets-Remove", "target-"),
+        ("Signed-By", "signed-by"),
+        ("Trusted", "trusted"),
+        ("Check-Valid-Until", "check-valid-until"),
+        ("By-Hash", "by-hash"),
+        ("PDiffs", "pdiffs"),
+    ] {
+        if map.contains_key(field) {
+            options
+                .set(key, map.remove_value(field).split_whitespace()?)
+                .with_context(|_| format_err!("processing field {:?}", field))?;
+        }
+    }
+
+    let mut ret = Vec::with_capacity(types.len() * urls.len() * suites.len());
+
+    for src in &types {
+        for src in parse_type(src)? {
+            for url in &urls {
+                for suite in &suites {
+                    ret.push(Entry {
+                        src: *src,
+                        url: with_trailing_slash(url),
+                        suite_codename: suite.to_string(),
+                        components: components.clone(),
+                        options: options.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Read `Entry` objects from a `deb822`-style sources file.
+///
+/// Each _Block_ may expand to multiple entries, as `Types`, `URIs` and `Suites`
+/// can all have multiple values.
+pub fn read_deb822<R: Read>(from: R) -> Result<Vec<Entry>, Error> {
+    let mut ret = Vec::new();
+
+    for (no, block) in rfc822::Blocks::new(from, "sources".to_string()).enumerate() {
+        let block = block.with_context(|_| format_err!("reading around stanza {}", no + 1))?;
+        ret.extend(read_stanza(&block).with_context(|_| format_err!("parsing stanza {}", no + 1))?);
+    }
+
+    Ok(ret)
+}
+
 #[cfg(test)]
 mod tests {
     use std::io;
 
     use super::read;
+    use super::read_deb822;
+    use super::ByHash;
     use super::Entry;
+    use super::ListOption;
+    use super::Options;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
 
     #[test]
     fn simple() {
@@ -109,14 +419,14 @@ mod tests {
             vec![
                 Entry {
                     src: false,
-                    arch: None,
+                    options: Options::default(),
                     url: "http://foo/".to_string(),
                     suite_codename: "bar".to_string(),
                     components: vec!["baz".to_string(), "quux".to_string()],
                 },
                 Entry {
                     src: true,
-                    arch: None,
+                    options: Options::default(),
                     url: "http://foo/".to_string(),
                     suite_codename: "bar".to_string(),
                     components: vec!["baz".to_string(), "quux".to_string()],
@@ -131,4 +441,151 @@ deb-src http://foo  bar  baz quux
             .unwrap()
         );
     }
+
+    #[test]
+    fn flat() {
+        let entries = read(io::Cursor::new("deb http://foo/repo ./")).unwrap();
+        assert_eq!(1, entries.len());
+        assert!(entries[0].is_flat());
+        assert!(entries[0].components.is_empty());
+        assert_eq!("./", entries[0].suite_codename);
+    }
+
+    #[test]
+    fn deb822() {
+        let entries = read_deb822(io::Cursor::new(
+            r"
+# a comment before anything
+Types: deb deb-src
+URIs: http://foo
+Suites: bar bar-updates
+Components: baz quux
+Architectures: amd64 i386
+Signed-By: /usr/share/keyrings/foo.gpg
+
+Enabled: no
+Types: deb
+URIs: http://disabled/
+Suites: bar
+Components: baz
+",
+        ))
+        .unwrap();
+
+        let entry = |src: bool, suite: &str| Entry {
+            src,
+            options: Options {
+                arches: ListOption {
+                    set: strings(&["amd64", "i386"]),
+                    ..ListOption::default()
+                },
+                signed_by: vec!["/usr/share/keyrings/foo.gpg".to_string()],
+                ..Options::default()
+            },
+            url: "http://foo/".to_string(),
+            suite_codename: suite.to_string(),
+            components: vec!["baz".to_string(), "quux".to_string()],
+        };
+
+        assert_eq!(
+            vec![
+                entry(false, "bar"),
+                entry(false, "bar-updates"),
+                entry(true, "bar"),
+                entry(true, "bar-updates"),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn deb822_inline_key() {
+        let entries = read_deb822(io::Cursor::new(
+            r"Types: deb
+URIs: http://foo
+Suites: bar
+Components: baz
+Signed-By:
+ -----BEGIN PGP PUBLIC KEY BLOCK-----
+ .
+ mQINBFyf
+ -----END PGP PUBLIC KEY BLOCK-----
+",
+        ))
+        .unwrap();
+
+        assert_eq!(
+            vec![concat!(
+                "-----BEGIN PGP PUBLIC KEY BLOCK-----\n",
+                "\n",
+                "mQINBFyf\n",
+                "-----END PGP PUBLIC KEY BLOCK-----"
+            )
+            .to_string()],
+            entries[0].options.signed_by
+        );
+    }
+
+    #[test]
+    fn options() {
+        let entries = read(io::Cursor::new(
+            r"
+deb [ arch=amd64,i386 signed-by=/usr/share/keyrings/foo.gpg trusted=yes by-hash=force ] http://foo bar baz
+deb [arch=arm64] http://foo bar baz # comment [arch=i386]
+",
+        ))
+        .unwrap();
+
+        assert_eq!(2, entries.len());
+        assert_eq!(
+            Options {
+                arches: ListOption {
+                    set: strings(&["amd64", "i386"]),
+                    ..ListOption::default()
+                },
+                signed_by: vec!["/usr/share/keyrings/foo.gpg".to_string()],
+                trusted: Some(true),
+                by_hash: Some(ByHash::Force),
+                ..Options::default()
+            },
+            entries[0].options
+        );
+        assert_eq!("http://foo/", entries[0].url);
+        assert_eq!(vec!["baz".to_string()], entries[0].components);
+        assert_eq!(strings(&["arm64"]), entries[1].options.arches.set);
+
+        assert!(read(io::Cursor::new("deb [arch=amd64 http://foo bar baz")).is_err());
+        assert!(read(io::Cursor::new("deb http://foo ./ baz")).is_err());
+        assert!(read(io::Cursor::new("deb [trusted=maybe] http://foo bar baz")).is_err());
+        assert!(read(io::Cursor::new("deb [trusted+=yes] http://foo bar baz")).is_err());
+    }
+
+    #[test]
+    fn adjusted_options() {
+        let entries = read(io::Cursor::new(
+            "deb [ arch+=armhf,arm64 arch-=i386 allow-insecure=yes snapshot=20240101T000000Z \
+             lang=en inrelease-path=InRelease ] http://foo bar baz",
+        ))
+        .unwrap();
+
+        let arches = &entries[0].options.arches;
+        assert_eq!(
+            strings(&["amd64", "armhf", "arm64"]),
+            arches.apply(&strings(&["amd64", "i386"]))
+        );
+        assert_eq!(strings(&["en"]), entries[0].options.langs.set);
+
+        let entries = read_deb822(io::Cursor::new(
+            "Types: deb\nURIs: http://foo\nSuites: bar\nComponents: baz\n\
+             Architectures-Remove: i386\nAllow-Insecure: yes\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            strings(&["amd64"]),
+            entries[0]
+                .options
+                .arches
+                .apply(&strings(&["amd64", "i386"]))
+        );
+    }
 }
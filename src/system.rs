@@ -10,33 +10,78 @@
 //! # }
 //! ```
 
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
 use std::env;
 use std::fs;
 use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::thread;
+use std::time;
 
+use chrono::Duration;
+use deb_version::compare_versions;
+use failure::ensure;
 use failure::err_msg;
 use failure::format_err;
 use failure::Error;
 use failure::ResultExt;
 use gpgrv::Keyring;
+use insideout::InsideOut;
 use reqwest;
-
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::archives;
+use crate::build_dep::BuildConfig;
+use crate::cache;
+use crate::cache::PackageCache;
+use crate::checksum::HashPolicy;
+use crate::fetch::Fetcher;
+use crate::fetch::Progress;
+use crate::fetch::Transport;
 use crate::lists;
+use crate::parse::Arch;
 use crate::parse::Package;
+use crate::policy::PackageFile;
+use crate::policy::Policy;
+use crate::policy::Version;
+use crate::preferences::Preference;
+use crate::rdepends::ReverseDependencies;
 use crate::release;
+use crate::resolve::Universe;
+use crate::resolve::Unsatisfiable;
 use crate::rfc822;
+use crate::rfc822::RfcMapExt;
 use crate::sources_list::Entry;
 
+pub use crate::lists::Compression;
+
 /// The core object, tying together configuration, caching, and listing.
 pub struct System {
     pub(crate) lists_dir: PathBuf,
+    archives_dir: PathBuf,
     dpkg_database: Option<PathBuf>,
     sources_entries: Vec<Entry>,
     arches: Vec<String>,
     keyring: Keyring,
-    client: reqwest::Client,
+    fetcher: Fetcher,
+    custom_transport: bool,
+    max_future: Duration,
+    compression: Vec<Compression>,
+    policy: Policy,
+}
+
+/// The default `reqwest::Client` configuration, honouring `http_proxy`.
+fn client_builder() -> Result<reqwest::ClientBuilder, Error> {
+    let builder = reqwest::Client::builder();
+    Ok(match env::var("http_proxy") {
+        Ok(proxy) => builder.proxy(reqwest::Proxy::http(&proxy)?),
+        Err(_) => builder,
+    })
 }
 
 /// A _Listing_ that has been downloaded, and the _Release_ it came from.
@@ -61,21 +106,27 @@ impl System {
     pub fn cache_only_in<P: AsRef<Path>>(lists_dir: P) -> Result<Self, Error> {
         fs::create_dir_all(lists_dir.as_ref())?;
 
-        let client = if let Ok(proxy) = env::var("http_proxy") {
-            reqwest::Client::builder()
-                .proxy(reqwest::Proxy::http(&proxy)?)
-                .build()?
-        } else {
-            reqwest::Client::new()
+        let client = client_builder()?.build()?;
+
+        // like `/var/lib/apt/lists` and `/var/cache/apt/archives`, but both in the cache
+        let archives_dir = match lists_dir.as_ref().parent() {
+            Some(parent) => parent.join("archives"),
+            None => lists_dir.as_ref().join("archives"),
         };
 
         Ok(System {
             lists_dir: lists_dir.as_ref().to_path_buf(),
+            archives_dir,
             dpkg_database: None,
             sources_entries: Vec::new(),
             arches: Vec::new(),
             keyring: Keyring::new(),
-            client,
+            fetcher: Fetcher::new(Box::new(client)),
+            custom_transport: false,
+            // matching apt's `Acquire::Max-FutureTime`
+            max_future: Duration::days(10),
+            compression: Compression::DEFAULT_ORDER.to_vec(),
+            policy: Policy::new(),
         })
     }
 
@@ -102,6 +153,13 @@ impl System {
         self.dpkg_database = Some(dpkg.as_ref().to_path_buf());
     }
 
+    /// Configure where downloaded `.deb` files are stored, e.g. `/var/cache/apt/archives`.
+    ///
+    /// By default, this is `archives` next to the lists directory.
+    pub fn set_archives_dir<P: AsRef<Path>>(&mut self, archives_dir: P) {
+        self.archives_dir = archives_dir.as_ref().to_path_buf();
+    }
+
     /// Load GPG keys from an old-style keyring (i.e. not a keybox file).
     ///
     /// Note that this will reject invalid keyring files, unlike other `*apt` implementations.
@@ -110,6 +168,101 @@ impl System {
         Ok(())
     }
 
+    /// Replace the [Transport] used to download files, which is a `reqwest::Client` by default.
+    pub fn set_transport<T: Transport + 'static>(&mut self, transport: T) {
+        self.fetcher.transport = Box::new(transport);
+        self.custom_transport = true;
+    }
+
+    /// Receive notifications about downloads. By default, nothing is reported.
+    ///
+    /// [crate::fetch::StderrProgress] prints a line as each download completes.
+    pub fn set_progress<P: Progress + 'static>(&mut self, progress: P) {
+        self.fetcher.progress = Box::new(progress);
+    }
+
+    /// Configure how many downloads may be in progress from each host at once.
+    pub fn set_connections_per_host(&mut self, connections: usize) {
+        self.fetcher.connections_per_host = connections;
+    }
+
+    /// Configure how many times to retry a download which failed in a way that might be
+    /// transient, e.g. a timeout or a truncated response, before trying the next mirror.
+    ///
+    /// The first retry is after `backoff`, which doubles for each subsequent retry.
+    pub fn set_retries(&mut self, retries: u32, backoff: time::Duration) {
+        self.fetcher.retries = retries;
+        self.fetcher.backoff = backoff;
+    }
+
+    /// Configure the default `reqwest::Client` transport's timeouts. `None` disables a timeout.
+    ///
+    /// `total` limits the whole request, including reading the body, so must allow for
+    /// the largest file on the slowest link. By default, there is no connect timeout,
+    /// and a 30 second total timeout.
+    ///
+    /// This rebuilds the default transport, so fails if [System::set_transport] has been
+    /// used; configure the timeouts on your own [Transport] instead.
+    pub fn set_timeouts(
+        &mut self,
+        connect: Option<time::Duration>,
+        total: Option<time::Duration>,
+    ) -> Result<(), Error> {
+        ensure!(
+            !self.custom_transport,
+            "timeouts can't be applied to a custom transport"
+        );
+
+        let mut builder = client_builder()?.timeout(total);
+        if let Some(connect) = connect {
+            builder = builder.connect_timeout(connect);
+        }
+        self.fetcher.transport = Box::new(builder.build()?);
+        Ok(())
+    }
+
+    /// Configure which hashes are acceptable for downloaded files.
+    ///
+    /// By default, files with only MD5 or SHA1 hashes are refused.
+    pub fn set_hash_policy(&mut self, policy: HashPolicy) {
+        self.fetcher.hash_policy = policy;
+    }
+
+    /// Configure how far in the future a _Release_ may be dated, to allow for clock skew.
+    ///
+    /// _Releases_ dated further in the future are rejected, as are expired _Releases_,
+    /// and _Releases_ older than the one previously downloaded.
+    pub fn set_max_future(&mut self, max_future: Duration) {
+        self.max_future = max_future;
+    }
+
+    /// Configure which compressed variants of _Listings_ to download, most preferred first.
+    ///
+    /// Variants which are not listed are never downloaded; `Compression::None` must be
+    /// included to allow uncompressed downloads.
+    pub fn set_compression_preference<I: IntoIterator<Item = Compression>>(
+        &mut self,
+        compression: I,
+    ) {
+        self.compression = compression.into_iter().collect();
+    }
+
+    /// Add preferences, e.g. from [crate::preferences::read], which adjust the priorities
+    /// used to pick [System::candidates].
+    pub fn add_preferences<I: IntoIterator<Item = Preference>>(&mut self, preferences: I) {
+        self.policy.add_preferences(preferences);
+    }
+
+    /// Configure a release to prefer, e.g. `stable`, like `APT::Default-Release`.
+    pub fn set_default_release<S: ToString>(&mut self, release: S) {
+        self.policy.set_default_release(release);
+    }
+
+    /// The configured preferences, and default release.
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
     /// Download any necessary _Listings_ for the configured _Sources Entries_.
     pub fn update(&self) -> Result<(), Error> {
         let requested =
@@ -117,25 +270,92 @@ impl System {
                 .with_context(|_| format_err!("parsing sources entries"))?;
 
         requested
-            .download(&self.lists_dir, &self.keyring, &self.client)
+            .download(
+                &self.lists_dir,
+                &self.keyring,
+                &self.fetcher,
+                self.max_future,
+            )
             .with_context(|_| format_err!("downloading releases"))?;
 
         let releases = requested
-            .parse(&self.lists_dir)
+            .parse(&self.lists_dir, self.max_future)
             .with_context(|_| format_err!("parsing releases"))?;
 
-        lists::download_files(&self.client, &self.lists_dir, &releases)
+        lists::download_files(&self.fetcher, &self.lists_dir, &releases, &self.compression)
             .with_context(|_| format_err!("downloading release content"))?;
 
+        self.update_cache()
+            .with_context(|_| format_err!("building package cache"))?;
+
         Ok(())
     }
 
+    fn cache_path(&self) -> PathBuf {
+        self.lists_dir.join("pkgcache.bin")
+    }
+
+    /// The local name of each configured _Listing_, which is the hash of its content,
+    /// and a digest of them all, for the package cache.
+    fn cache_key(&self, listings: &[DownloadedList]) -> Result<(Vec<String>, cache::Key), Error> {
+        let mut names = Vec::with_capacity(listings.len());
+        let mut key = Sha256::new();
+        for list in listings {
+            let name =
+                lists::find_file_easy(&list.release, &list.listing, &Compression::DEFAULT_ORDER)?
+                    .local_name();
+            key.input(list.listing.name.as_bytes());
+            key.input(b" ");
+            key.input(name.as_bytes());
+            key.input(b"\n");
+            names.push(name);
+        }
+        Ok((names, key.result().into()))
+    }
+
+    /// Build the package cache for the configured _Listings_. This is done by `update`.
+    pub fn update_cache(&self) -> Result<(), Error> {
+        let listings = self.listings()?;
+        let (names, key) = self.cache_key(&listings)?;
+
+        let mut builder = cache::Builder::new();
+        for (list, name) in listings.iter().zip(names) {
+            builder.add_listing(&name)?;
+            let is_source = "Sources" == list.listing.name;
+            for block in self.open_listing(list)? {
+                let block = block?;
+                builder
+                    .add_block(block.as_str(), is_source)
+                    .with_context(|_| format_err!("adding {}", block.locality))?;
+            }
+        }
+
+        builder.write(&key, self.cache_path())
+    }
+
+    /// Open the package cache built by `update`, which must be for the configured _Listings_.
+    pub fn package_cache(&self) -> Result<PackageCache, Error> {
+        let (_, key) = self.cache_key(&self.listings()?)?;
+
+        let path = self.cache_path();
+        ensure!(path.is_file(), "no package cache; run update");
+
+        let cache = PackageCache::open(&path)
+            .with_context(|_| format_err!("invalid package cache; run update"))?;
+        ensure!(
+            key[..] == *cache.key(),
+            "package cache is for different listings; run update"
+        );
+
+        Ok(cache)
+    }
+
     /// Explain the configured _Listings_.
     pub fn listings(&self) -> Result<Vec<DownloadedList>, Error> {
         let releases =
             release::RequestedReleases::from_sources_lists(&self.sources_entries, &self.arches)
                 .with_context(|_| format_err!("parsing sources entries"))?
-                .parse(&self.lists_dir)
+                .parse(&self.lists_dir, self.max_future)
                 .with_context(|_| format_err!("parsing releases"))?;
 
         let mut ret = Vec::with_capacity(releases.len() * 4);
@@ -159,6 +379,403 @@ impl System {
         })
     }
 
+    /// Download the `.deb` files for some binary _Packages_, from the _Listings_ they were
+    /// found in, returning where each was stored.
+    ///
+    /// Files already in the archives directory, with the right size and hashes,
+    /// are not downloaded again.
+    pub fn download_debs(
+        &self,
+        packages: &[(&DownloadedList, &Package)],
+    ) -> Result<Vec<PathBuf>, Error> {
+        let packages: Vec<_> = packages
+            .iter()
+            .map(|(list, package)| (&list.release, *package))
+            .collect();
+
+        archives::download_debs(&self.fetcher, &self.archives_dir, &packages)
+    }
+
+    /// Find a binary _Package_ in the configured _Listings_, and download its `.deb` file.
+    pub fn download_deb(&self, name: &str, version: &str, arch: &str) -> Result<PathBuf, Error> {
+        let (list, package) = self
+            .find_binary(name, version, arch)?
+            .ok_or_else(|| format_err!("{} {} ({}) not found", name, version, arch))?;
+
+        Ok(self
+            .download_debs(&[(&list, &package)])?
+            .pop()
+            .expect("one package in, one path out"))
+    }
+
+    fn find_binary(
+        &self,
+        name: &str,
+        version: &str,
+        arch: &str,
+    ) -> Result<Option<(DownloadedList, Package)>, Error> {
+        for list in self.listings()? {
+            if "Packages" != list.listing.name {
+                continue;
+            }
+
+            for block in self.open_listing(&list)? {
+                let block = block?;
+                let map = block.as_map()?;
+                let matches = Some(name) == map.get_value("Package").one_line()?
+                    && Some(version) == map.get_value("Version").one_line()?
+                    && map
+                        .get_value("Architecture")
+                        .one_line()?
+                        .is_some_and(|found| arch == found || "all" == found);
+
+                if matches {
+                    let package = block.as_pkg()?;
+                    return Ok(Some((list, package)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Download all the files making up a source _Package_ (like `apt-get source`, but
+    /// without unpacking), from the _Listing_ it was found in, into `dest_dir`.
+    ///
+    /// Returns where each file was stored.
+    pub fn download_source_package<P: AsRef<Path>>(
+        &self,
+        list: &DownloadedList,
+        package: &Package,
+        dest_dir: P,
+    ) -> Result<Vec<PathBuf>, Error> {
+        archives::download_source(&self.fetcher, dest_dir.as_ref(), &list.release, package)
+    }
+
+    /// Find a source _Package_ in the configured _Listings_, and download its files.
+    ///
+    /// If `version` is not specified, the highest available version is used.
+    pub fn download_source<P: AsRef<Path>>(
+        &self,
+        name: &str,
+        version: Option<&str>,
+        dest_dir: P,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let (list, package) = self
+            .find_source(name, version)?
+            .ok_or_else(|| format_err!("source package {} {:?} not found", name, version))?;
+
+        self.download_source_package(&list, &package, dest_dir)
+    }
+
+    fn find_source(
+        &self,
+        name: &str,
+        version: Option<&str>,
+    ) -> Result<Option<(DownloadedList, Package)>, Error> {
+        let mut best: Option<(DownloadedList, Package)> = None;
+
+        for list in self.listings()? {
+            if "Sources" != list.listing.name {
+                continue;
+            }
+
+            for block in self.open_listing(&list)? {
+                let block = block?;
+                let map = block.as_map()?;
+                if Some(name) != map.get_value("Package").one_line()? {
+                    continue;
+                }
+
+                let found = map.get_value("Version").one_line_req()?;
+                let wanted = match (version, &best) {
+                    (Some(version), _) => version == found,
+                    (None, Some((_, best))) => {
+                        Ordering::Greater == deb_version::compare_versions(found, &best.version)
+                    }
+                    (None, None) => true,
+                };
+
+                if wanted {
+                    best = Some((list.clone(), block.as_pkg()?));
+                }
+            }
+        }
+
+        Ok(best)
+    }
+
+    /// Collect the binary _Packages_ for the primary architecture from the configured
+    /// _Listings_, and from the `dpkg` database, if set, to plan changes against.
+    pub fn universe(&self) -> Result<Universe, Error> {
+        let wanted = self.primary_arch_filter()?;
+        let mut universe = self.available(&wanted)?;
+
+        for package in self.installed()? {
+            if wanted(&package) {
+                universe.add_installed(package)?;
+            }
+        }
+
+        Ok(universe)
+    }
+
+    /// The installed binary _Packages_ from the `dpkg` database, if set.
+    fn installed(&self) -> Result<Vec<Package>, Error> {
+        let mut ret = Vec::new();
+        if self.dpkg_database.is_none() {
+            return Ok(ret);
+        }
+
+        for block in self.open_status()? {
+            let package = block?.as_pkg()?;
+            let installed = package
+                .as_bin()
+                .and_then(|bin| bin.status.as_ref())
+                .is_some_and(|status| status.ends_with(" installed"));
+
+            if installed {
+                ret.push(package);
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Every version of every binary _Package_ for the primary architecture, by name,
+    /// highest version first, with where each is available from: the configured _Listings_,
+    /// and the `dpkg` database, if set.
+    pub fn versions(&self) -> Result<BTreeMap<String, Vec<Version>>, Error> {
+        self.versions_where(|_| true, self.primary_arch_filter()?)
+    }
+
+    /// Like [System::versions], but only for the packages with these names, and for every
+    /// configured architecture. The versions for other architectures than the primary one
+    /// are keyed by `name:arch`, like `apt-cache policy`.
+    pub fn versions_of<S: AsRef<str>>(
+        &self,
+        names: &[S],
+    ) -> Result<BTreeMap<String, Vec<Version>>, Error> {
+        self.versions_where(
+            |name| names.iter().any(|wanted| wanted.as_ref() == name),
+            |_| true,
+        )
+    }
+
+    fn versions_where<F: Fn(&str) -> bool, W: Fn(&Package) -> bool>(
+        &self,
+        named: F,
+        wanted: W,
+    ) -> Result<BTreeMap<String, Vec<Version>>, Error> {
+        let mut ret: BTreeMap<String, Vec<Version>> = BTreeMap::new();
+
+        let arches = self
+            .arches
+            .iter()
+            .map(|arch| Ok((arch.as_str(), arch.parse::<Arch>()?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut add = |package: Package, file: &Rc<PackageFile>| {
+            let key = match arch_qualified(&package.name, &package.arches, &arches) {
+                Some(key) => key,
+                None => return,
+            };
+            let versions = ret.entry(key).or_default();
+            match versions.binary_search_by(|version| {
+                compare_versions(&package.version, &version.package.version)
+            }) {
+                Ok(pos) => versions[pos].files.push(Rc::clone(file)),
+                Err(pos) => versions.insert(
+                    pos,
+                    Version {
+                        package,
+                        files: vec![Rc::clone(file)],
+                    },
+                ),
+            }
+        };
+
+        for list in self.listings()? {
+            if "Packages" != list.listing.name {
+                continue;
+            }
+
+            let file = Rc::new(PackageFile::from_list(&list));
+            for block in self.open_listing(&list)? {
+                let block = block?;
+                let mut map = block.as_map()?;
+
+                // most packages aren't wanted; skip parsing them
+                if !named(map.get_value("Package").one_line_req()?) {
+                    continue;
+                }
+
+                let package = Package::parse(&mut map)?;
+                if wanted(&package) {
+                    add(package, &file);
+                }
+            }
+        }
+
+        let status = Rc::new(PackageFile::dpkg_status());
+        for package in self.installed()? {
+            if named(&package.name) && wanted(&package) {
+                add(package, &status);
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// The version of each binary _Package_ which would be installed, according to the
+    /// configured preferences, like the `Candidate` from `apt-cache policy`.
+    ///
+    /// Packages with no acceptable version are left out.
+    pub fn candidates(&self) -> Result<BTreeMap<String, Version>, Error> {
+        Ok(self
+            .versions()?
+            .into_iter()
+            .filter_map(|(name, versions)| {
+                self.policy
+                    .candidate(&versions)
+                    .cloned()
+                    .map(|candidate| (name, candidate))
+            })
+            .collect())
+    }
+
+    /// Whether a _Package_ is for the primary architecture, or any architecture.
+    fn primary_arch_filter(&self) -> Result<impl Fn(&Package) -> bool, Error> {
+        let primary: Option<Arch> = self.arches.first().map(|arch| arch.parse()).inside_out()?;
+
+        Ok(move |package: &Package| match primary {
+            Some(primary) => package
+                .arches
+                .iter()
+                .any(|arch| arch.is_any() || primary == *arch),
+            None => true,
+        })
+    }
+
+    /// The binary _Packages_ from the configured _Listings_ which are `wanted`.
+    fn available<F: Fn(&Package) -> bool>(&self, wanted: F) -> Result<Universe, Error> {
+        let mut universe = Universe::new();
+
+        for list in self.listings()? {
+            if "Packages" != list.listing.name {
+                continue;
+            }
+
+            for block in self.open_listing(&list)? {
+                let package = block?.as_pkg()?;
+                if wanted(&package) {
+                    universe.add_available(package)?;
+                }
+            }
+        }
+
+        Ok(universe)
+    }
+
+    /// Check the build dependencies of every source _Package_ in the configured _Listings_
+    /// which builds on `config.arch`, against the binary _Packages_ available for it.
+    ///
+    /// The installed packages are not considered. Returns the source _Packages_ which can't
+    /// be built, and why.
+    pub fn build_dep_check(
+        &self,
+        config: &BuildConfig,
+    ) -> Result<Vec<(Package, Unsatisfiable)>, Error> {
+        let universe = self.available(|package| {
+            package
+                .arches
+                .iter()
+                .any(|arch| arch.is_any() || config.arch == *arch)
+        })?;
+
+        let mut ret = Vec::new();
+
+        for list in self.listings()? {
+            if "Sources" != list.listing.name {
+                continue;
+            }
+
+            for block in self.open_listing(&list)? {
+                let block = block?;
+                let mut map = block.as_map()?;
+                if !config.builds(map.get_value("Architecture").one_line_req()?)? {
+                    continue;
+                }
+
+                let source = Package::parse(&mut map)?;
+
+                if let Err(e) = config.check(&universe, &source) {
+                    ret.push((source, e.downcast::<Unsatisfiable>()?));
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Index what refers to each package, from all the binary _Listings_, and, with
+    /// `sources`, the build dependencies from the source _Listings_.
+    pub fn reverse_dependencies(&self, sources: bool) -> Result<ReverseDependencies, Error> {
+        let mut index = ReverseDependencies::new();
+
+        for list in self.listings()? {
+            match list.listing.name.as_str() {
+                "Packages" => (),
+                "Sources" if sources => (),
+                _ => continue,
+            }
+
+            for block in self.open_listing(&list)? {
+                index.add(&block?.as_pkg()?);
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Check whether each binary _Package_ for the primary architecture in the configured
+    /// _Listings_ can be installed, given the rest of the _Listings_, using all available cpus.
+    ///
+    /// The installed packages are not considered. Returns the _Packages_ which can't be
+    /// installed, and why.
+    pub fn installability_check(&self) -> Result<Vec<(Package, Unsatisfiable)>, Error> {
+        let universe = self.available(self.primary_arch_filter()?)?;
+        let packages: Vec<&Package> = universe.packages().collect();
+
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk = (packages.len() / threads).max(1);
+
+        let results = thread::scope(|scope| {
+            let workers: Vec<_> = packages
+                .chunks(chunk)
+                .map(|packages| {
+                    let universe = &universe;
+                    scope.spawn(move || -> Result<Vec<_>, Error> {
+                        let mut broken = Vec::new();
+                        for &package in packages {
+                            if let Err(e) = universe.check_installable(package) {
+                                broken.push((package.clone(), e.downcast::<Unsatisfiable>()?));
+                            }
+                        }
+                        Ok(broken)
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("checking doesn't panic"))
+                .collect::<Result<Vec<_>, Error>>()
+        })?;
+
+        Ok(results.into_iter().flatten().collect())
+    }
+
     /// Open the `dpkg` `status` database, to access the packages inside it.
     pub fn open_status(&self) -> Result<ListingBlocks, Error> {
         let mut status = self
@@ -174,6 +791,28 @@ impl System {
     }
 }
 
+/// The name `apt-cache policy` shows for a binary _Package_ with these `arches`: `name`
+/// for the primary architecture, and `all`, or `name:arch` for the other configured
+/// architectures. `None` if it's for none of them.
+fn arch_qualified(
+    name: &str,
+    arches: &HashSet<Arch>,
+    configured: &[(&str, Arch)],
+) -> Option<String> {
+    if configured.is_empty() || arches.iter().any(|arch| arch.is_any()) {
+        return Some(name.to_string());
+    }
+
+    let pos = configured
+        .iter()
+        .position(|(_, arch)| arches.contains(arch))?;
+
+    Some(match pos {
+        0 => name.to_string(),
+        _ => format!("{}:{}", name, configured[pos].0),
+    })
+}
+
 /// The _Blocks_ of a _Listing_.
 pub struct ListingBlocks {
     pub(crate) inner: rfc822::Blocks<fs::File>,
@@ -208,7 +847,39 @@ impl NamedBlock {
         Package::parse(&mut self.as_map()?)
     }
 
+    /// The _Block_ exactly as it was in the _Listing_.
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+
     pub fn into_string(self) -> String {
         self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::parse::Arch;
+
+    use super::arch_qualified;
+
+    #[test]
+    fn qualified_names() {
+        let arch = |arch: &str| arch.parse::<Arch>().unwrap();
+        let arches =
+            |arch: &str| -> HashSet<Arch> { vec![arch.parse().unwrap()].into_iter().collect() };
+        let configured = [("amd64", arch("amd64")), ("i386", arch("i386"))];
+        let qualified = |of: &str| arch_qualified("foo", &arches(of), &configured);
+
+        assert_eq!(Some("foo".to_string()), qualified("amd64"));
+        assert_eq!(Some("foo".to_string()), qualified("all"));
+        assert_eq!(Some("foo:i386".to_string()), qualified("i386"));
+        assert_eq!(None, qualified("arm64"));
+        assert_eq!(
+            Some("foo".to_string()),
+            arch_qualified("foo", &arches("i386"), &[])
+        );
+    }
+}